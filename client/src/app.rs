@@ -1,74 +1,239 @@
+mod controls;
+#[cfg(not(target_arch = "wasm32"))]
+mod discovery;
+#[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+mod gamepad;
 mod throttle;
+mod toast;
 
-use crate::app::throttle::Throttle;
+use crate::app::controls::{Action, Controls, Input};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::discovery::Discovery;
+use crate::app::throttle::{Momentum, Throttle};
+use crate::app::toast::Toasts;
 use chrono::NaiveDateTime;
-use eframe::egui::{Align, Button, Context, Layout};
+use eframe::egui::{Align, Align2, Button, Context, Id, LayerId, Layout, Order, Pos2, Vec2};
 use eframe::{egui, Frame, Storage};
 use egui::{Grid, TextEdit, Ui, Window};
 use ewebsock::{WsEvent, WsMessage, WsReceiver, WsSender};
-use jmri_throttle_rs::message::{Address, WiMessage, WiMessageType};
+use jmri_throttle_rs::message::{
+    Address, AddressKind, LocoAddress, Target, ThrottleId, Velocity, WiMessage, WiMessageType,
+    DEFAULT_SHORT_ADDRESS_LIMIT,
+};
+use jmri_throttle_rs::protocol::{
+    ClientMessage, Encoding, Hello, Payload, Permissions, RequestId, ServerMessage,
+};
+use jmri_throttle_rs::server_info::{Notification, ServerInfo};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use uuid::Uuid;
 
+/// Screens narrower than this, in points, get one throttle at a time instead of windows.
+const MOBILE_WIDTH: f32 = 600.0;
+/// How far a finger has to travel sideways for a swipe to move to another throttle.
+const SWIPE_DISTANCE: f32 = 80.0;
+
+/// Slots in the rack until the user picks another number, A to C like a handheld.
+const DEFAULT_SLOTS: usize = 3;
+/// Slots go from A up to H at most.
+const MAX_SLOTS: usize = 8;
+
+/// Storage key of the keyboard and gamepad bindings.
+const CONTROLS_KEY: &str = "controls";
+/// Storage key of each loco's momentum settings.
+const MOMENTUM_KEY: &str = "momentum";
+/// Storage key of the [Session].
+const SESSION_KEY: &str = "session";
+
+/// Where the client was connected to and what it was driving, saved so it can carry on after
+/// a reload. Window positions are kept by egui itself.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+struct Session {
+    url: Option<String>,
+    encoding: Encoding,
+    slots: usize,
+    /// The locos in each slot that held any.
+    rack: BTreeMap<ThrottleId, Vec<LocoAddress>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            url: None,
+            encoding: Encoding::default(),
+            slots: DEFAULT_SLOTS,
+            rack: BTreeMap::new(),
+        }
+    }
+}
+
+/// The rack's slots, lettered from A.
+fn rack(slots: usize) -> Vec<Throttle> {
+    (b'A'..)
+        .take(slots.clamp(1, MAX_SLOTS))
+        .filter_map(|letter| ThrottleId::try_from(char::from(letter)).ok())
+        .map(Throttle::new)
+        .collect()
+}
+
 pub struct WsConnection {
     pub ws_sender: WsSender,
     pub ws_receiver: WsReceiver,
+    encoding: Encoding,
+    next_request_id: RequestId,
+    pending: HashMap<RequestId, WiMessage>,
 }
 
 impl WsConnection {
+    pub fn new(ws_sender: WsSender, ws_receiver: WsReceiver, encoding: Encoding) -> Self {
+        Self {
+            ws_sender,
+            ws_receiver,
+            encoding,
+            next_request_id: 1,
+            pending: HashMap::new(),
+        }
+    }
+
     pub fn send(&mut self, message: WiMessage) {
-        let message = serde_json::to_string(&message).unwrap();
-        self.ws_sender.send(WsMessage::Text(message));
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.pending.insert(request_id, message);
+
+        self.send_encoded(&ClientMessage::new(Some(request_id), message));
+    }
+
+    /// Logs in, which has to come before any command.
+    fn hello(&mut self, token: &str) {
+        let token = (!token.is_empty()).then(|| token.to_string());
+        self.send_encoded(&Hello { token });
+    }
+
+    fn send_encoded<T: Serialize>(&mut self, value: &T) {
+        let message = match self.encoding.encode(value) {
+            Payload::Text(text) => WsMessage::Text(text),
+            Payload::Binary(bytes) => WsMessage::Binary(bytes),
+        };
+        self.ws_sender.send(message);
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<ServerMessage, String> {
+        match message {
+            WsMessage::Text(text) => self.encoding.decode(text.as_bytes()),
+            WsMessage::Binary(bytes) => self.encoding.decode(&bytes),
+            unknown => Err(format!("Unknown WsMessage: {unknown:?}")),
+        }
+    }
+
+    /// Removes and returns the message a server reply refers to.
+    fn resolve(&mut self, request_id: RequestId) -> Option<WiMessage> {
+        self.pending.remove(&request_id)
     }
 }
 
 #[derive(Default, Debug)]
 struct State {
     pub show_connect: bool,
-    pub show_new_throttle: bool,
+    /// The slot the add loco window adds to, while it's open.
+    pub add_to_slot: Option<usize>,
     pub new_address: String,
+    /// Picked by the user, otherwise guessed from the number.
+    pub new_address_kind: Option<AddressKind>,
     pub connecting: bool,
+    pub show_about: bool,
+    pub show_restore: bool,
+    /// Where the pointer went down, while it could still be a swipe.
+    pub swipe_start: Option<Pos2>,
 }
 
 pub struct App {
     uuid: Uuid,
     url: String,
-    throttles: HashMap<Address, Throttle>,
+    encoding: Encoding,
+    /// PIN or token, only sent when not empty and never saved.
+    token: String,
+    /// Who the server says we're logged in as.
+    user: Option<String>,
+    permissions: Permissions,
+    server_info: Option<ServerInfo>,
+    /// JMRI alerts waiting to be dismissed, oldest first.
+    alerts: Vec<String>,
+    /// One throttle per slot, each on its own WiThrottle multi-throttle.
+    rack: Vec<Throttle>,
+    /// The slot picked last, which keys and gamepads drive.
+    active: usize,
+    controls: Controls,
+    /// Momentum settings of every loco driven so far, used again when it's next added.
+    momentum: BTreeMap<LocoAddress, Momentum>,
+    /// Slots from the last session or connection, offered to be acquired again.
+    restore: BTreeMap<ThrottleId, Vec<LocoAddress>>,
     connection: Option<WsConnection>,
     time: i64,
     state: State,
+    toasts: Toasts,
+    #[cfg(not(target_arch = "wasm32"))]
+    discovery: Option<Discovery>,
 }
 
 impl App {
     #[allow(dead_code)]
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         let mut uuid: Option<Uuid> = None;
+        let mut bindings = None;
+        let mut momentum = None;
+        let mut session = Session::default();
         if let Some(storage) = cc.storage {
             if let Some(state) = eframe::get_value(storage, eframe::APP_KEY) {
                 uuid = state;
             }
+            bindings = eframe::get_value(storage, CONTROLS_KEY);
+            momentum = eframe::get_value(storage, MOMENTUM_KEY);
+            session = eframe::get_value(storage, SESSION_KEY).unwrap_or_default();
         }
         Self {
             uuid: uuid.unwrap_or_else(Uuid::new_v4),
-            url: "localhost:4000/ws".to_string(),
+            url: session
+                .url
+                .unwrap_or_else(|| "localhost:4000/ws".to_string()),
+            encoding: session.encoding,
+            token: String::new(),
+            user: None,
+            permissions: Permissions::default(),
+            server_info: None,
+            alerts: Vec::new(),
             connection: None,
             time: 0,
-            throttles: Default::default(),
+            rack: rack(session.slots),
+            active: 0,
+            controls: Controls::new(bindings.unwrap_or_default()),
+            momentum: momentum.unwrap_or_default(),
+            restore: session.rack,
             state: State::default(),
+            toasts: Toasts::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            discovery: Discovery::start(),
         }
     }
 
     fn connect(&mut self, ctx: &Context) {
         let ctx = ctx.clone();
         let wakeup = move || ctx.request_repaint();
-        match ewebsock::connect_with_wakeup(format!("ws://{}", self.url), wakeup) {
+        // Plain ws:// unless the URL says otherwise, e.g. wss:// for a server with TLS
+        let url = match self.url.contains("://") {
+            true => self.url.clone(),
+            false => format!("ws://{}", self.url),
+        };
+        let url = match self.encoding {
+            Encoding::Json => url,
+            encoding => format!("{url}?encoding={encoding}"),
+        };
+        match ewebsock::connect_with_wakeup(url, wakeup) {
             Ok((ws_sender, ws_receiver)) => {
-                self.connection = Some(WsConnection {
-                    ws_sender,
-                    ws_receiver,
-                })
+                self.connection = Some(WsConnection::new(ws_sender, ws_receiver, self.encoding))
             }
             Err(e) => {
                 error!("Failed to connect to {}: {e}", self.url)
@@ -77,10 +242,88 @@ impl App {
     }
 
     fn disconnect(&mut self) {
-        self.throttles.clear();
+        let held = self.held();
+        if !held.is_empty() {
+            self.restore = held;
+        }
+        self.remember_momentum();
+        for throttle in &mut self.rack {
+            *throttle = Throttle::new(throttle.slot);
+        }
+        self.user = None;
+        self.permissions = Permissions::default();
+        self.server_info = None;
+        self.alerts.clear();
         self.connection = None;
         self.state.connecting = false;
         self.state.show_connect = false;
+        self.state.show_restore = false;
+    }
+
+    /// The locos in each slot holding any.
+    fn held(&self) -> BTreeMap<ThrottleId, Vec<LocoAddress>> {
+        self.rack
+            .iter()
+            .filter(|throttle| !throttle.is_empty())
+            .map(|throttle| (throttle.slot, throttle.addresses.clone()))
+            .collect()
+    }
+
+    /// Keeps each slot's momentum settings under its lead loco.
+    fn remember_momentum(&mut self) {
+        for throttle in &self.rack {
+            if let Some(lead) = throttle.lead() {
+                self.momentum.insert(lead, throttle.momentum);
+            }
+        }
+    }
+
+    /// Changes the number of slots, never dropping one that holds locos.
+    fn set_slots(&mut self, slots: usize) {
+        let in_use = self.rack.iter().rposition(|throttle| !throttle.is_empty());
+        let slots = slots.max(in_use.map_or(1, |last| last + 1));
+        let mut rack = rack(slots);
+        let kept = self.rack.len().min(rack.len());
+        rack.splice(..kept, self.rack.drain(..kept));
+        self.rack = rack;
+        self.active = self.active.min(self.rack.len() - 1);
+        self.state.add_to_slot = self
+            .state
+            .add_to_slot
+            .filter(|index| *index < self.rack.len());
+    }
+
+    /// Makes a slot the active one, bringing its window to the front.
+    fn activate(&mut self, ctx: &Context, index: usize) {
+        self.active = index;
+        let layer = LayerId::new(Order::Middle, slot_window_id(self.rack[index].slot));
+        ctx.move_to_top(layer);
+    }
+
+    /// Acquires an address on a slot's multi-throttle and adds it there straight away. It's
+    /// taken out again if the server replies with an error. A loco already in another slot
+    /// moves over, which the server does by releasing it on the old multi-throttle.
+    fn add_loco(&mut self, index: usize, address: LocoAddress) {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        let Some(slot) = self.rack.get(index).map(|throttle| throttle.slot) else {
+            return;
+        };
+        connection.send(WiMessage::new(address, WiMessageType::AddAddress).with_throttle(slot));
+        for throttle in self
+            .rack
+            .iter_mut()
+            .filter(|throttle| throttle.slot != slot)
+        {
+            if throttle.lead() == Some(address) {
+                self.momentum.insert(address, throttle.momentum);
+            }
+            throttle.remove(address);
+        }
+        let momentum = self.momentum.get(&address).copied().unwrap_or_default();
+        self.rack[index].add(address, momentum);
+        self.active = index;
     }
 
     fn handle_messages(&mut self, _ctx: &Context) {
@@ -96,15 +339,13 @@ impl App {
             match event {
                 WsEvent::Opened => {
                     info!("Connection opened");
+                    connection.hello(&self.token);
                     self.state.connecting = false;
                     self.state.show_connect = false;
                 }
-                WsEvent::Message(message) => match message {
-                    WsMessage::Text(message) => match serde_json::from_str::<WiMessage>(&message) {
-                        Ok(message) => messages.push(message),
-                        Err(e) => error!("Failed to parse message: {e}"),
-                    },
-                    unknown => error!("Unknown WsMessage: {unknown:?}"),
+                WsEvent::Message(message) => match connection.decode(message) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => error!("Failed to parse message: {e}"),
                 },
                 WsEvent::Error(e) => error!("WS error: {e}"),
                 WsEvent::Closed => {
@@ -117,34 +358,389 @@ impl App {
         if closed {
             self.disconnect();
         }
-        messages.iter().for_each(|m| self.handle_message(m));
+        messages
+            .into_iter()
+            .for_each(|m| self.handle_server_message(m));
+    }
+
+    fn handle_server_message(&mut self, message: ServerMessage) {
+        match message {
+            ServerMessage::Welcome { user, permissions } => {
+                info!("Logged in as {user}");
+                self.user = Some(user);
+                self.permissions = permissions;
+                self.state.show_restore = !self.restore.is_empty() && !self.permissions.view_only;
+            }
+            ServerMessage::Update(message) => self.handle_message(&message),
+            ServerMessage::Ack { request_id } => {
+                if let Some(connection) = self.connection.as_mut() {
+                    connection.resolve(request_id);
+                }
+            }
+            ServerMessage::Error { request_id, error } => {
+                let sent = request_id
+                    .zip(self.connection.as_mut())
+                    .and_then(|(request_id, connection)| connection.resolve(request_id));
+                match sent {
+                    Some(sent) => {
+                        // The loco was added optimistically, take it out if the server refused
+                        if let (WiMessageType::AddAddress, Target::Loco(address)) =
+                            (sent.message_type, sent.address)
+                        {
+                            if let Some(throttle) = self.slot_mut(sent.throttle) {
+                                throttle.remove(address);
+                            }
+                        }
+                        self.toasts
+                            .error(format!("Address {}: {error}", sent.address));
+                    }
+                    None => self.toasts.error(error.to_string()),
+                }
+            }
+            ServerMessage::ServerInfo(server_info) => self.server_info = Some(server_info),
+            ServerMessage::Notification(Notification::Alert(alert)) => self.alerts.push(alert),
+            ServerMessage::Notification(Notification::Info(info)) => self.toasts.info(info),
+        }
+    }
+
+    fn about_window(&mut self, ctx: &Context) {
+        let info = self.server_info.clone().unwrap_or_default();
+        let unknown = || "Unknown".to_string();
+        Window::new("About server")
+            .open(&mut self.state.show_about)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                Grid::new("AboutGrid").num_columns(2).show(ui, |ui| {
+                    ui.label("Server:");
+                    ui.label(info.server_type.unwrap_or_else(unknown));
+                    ui.end_row();
+                    ui.label("Version:");
+                    ui.label(info.description.unwrap_or_else(unknown));
+                    ui.end_row();
+                    ui.label("WiThrottle protocol:");
+                    ui.label(info.protocol_version.unwrap_or_else(unknown));
+                    ui.end_row();
+                    ui.label("Web port:");
+                    ui.label(info.web_port.map_or_else(unknown, |port| port.to_string()));
+                    ui.end_row();
+                });
+            });
+    }
+
+    /// Asks whether to acquire the locos that were in the rack before again.
+    fn restore_window(&mut self, ctx: &Context) {
+        if !self.state.show_restore {
+            return;
+        }
+        let mut answered = None;
+        Window::new("Restore throttles")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label("These slots held locos last time:");
+                Grid::new("RestoreGrid").num_columns(2).show(ui, |ui| {
+                    for (slot, addresses) in &self.restore {
+                        ui.strong(format!("{slot}:"));
+                        let addresses: Vec<String> =
+                            addresses.iter().map(|a| a.to_string()).collect();
+                        ui.label(addresses.join(" + "));
+                        ui.end_row();
+                    }
+                });
+                ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
+                    if ui.button("Acquire again").clicked() {
+                        answered = Some(true);
+                    }
+                    if ui.button("Forget them").clicked() {
+                        answered = Some(false);
+                    }
+                });
+            });
+        let Some(acquire) = answered else {
+            return;
+        };
+        let restore = std::mem::take(&mut self.restore);
+        if acquire {
+            // Slots the rack has been shrunk below since come back
+            let slots = restore
+                .keys()
+                .filter_map(|slot| self.slot_index(*slot))
+                .max();
+            if let Some(last) = slots {
+                self.set_slots(self.rack.len().max(last + 1));
+            }
+            for (slot, addresses) in restore {
+                let Some(index) = self.slot_index(slot) else {
+                    continue;
+                };
+                for address in addresses {
+                    self.add_loco(index, address);
+                }
+            }
+            self.active = 0;
+        }
+        self.state.show_restore = false;
+    }
+
+    /// Shows JMRI's alerts one at a time until they're dismissed.
+    fn alert_window(&mut self, ctx: &Context) {
+        let Some(alert) = self.alerts.first() else {
+            return;
+        };
+        let mut dismissed = false;
+        Window::new("JMRI")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(alert);
+                ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
+                    dismissed = ui.button("OK").clicked();
+                });
+            });
+        if dismissed {
+            self.alerts.remove(0);
+        }
     }
 
     fn handle_message(&mut self, message: &WiMessage) {
         use WiMessageType::*;
-        if let Time(t) = message.message_type {
+        if let Time(t, _) = message.message_type {
             self.time = t;
             return;
         }
-        if let Some(throttle) = self.throttles.get_mut(&message.address) {
-            match message.message_type {
-                AddAddress => {}
-                RemoveAddress => {
-                    self.throttles.remove(&message.address);
+        // Each loco is in one slot, the multi-throttle only matters for `*`
+        let slot = self.rack.iter_mut().find(|throttle| match message.address {
+            Target::Loco(address) => throttle.addresses.contains(&address),
+            Target::All => throttle.slot == message.throttle,
+        });
+        let Some(throttle) = slot else {
+            return;
+        };
+        let addresses = match message.address {
+            Target::Loco(address) => vec![address],
+            Target::All => throttle.addresses.clone(),
+        };
+        if message.message_type != RemoveAddress {
+            throttle.apply(message.message_type);
+            return;
+        }
+        for address in addresses {
+            if throttle.lead() == Some(address) {
+                self.momentum.insert(address, throttle.momentum);
+            }
+            throttle.remove(address);
+        }
+    }
+
+    /// Where a slot is in the rack, if it has that many slots.
+    fn slot_index(&self, slot: ThrottleId) -> Option<usize> {
+        self.rack.iter().position(|throttle| throttle.slot == slot)
+    }
+
+    fn slot_mut(&mut self, slot: ThrottleId) -> Option<&mut Throttle> {
+        self.rack.iter_mut().find(|throttle| throttle.slot == slot)
+    }
+
+    /// Drives the active slot with the keyboard and gamepads, or switches to another one.
+    fn apply_controls(&mut self, ctx: &Context) {
+        let inputs = self.controls.poll(ctx);
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        if inputs.is_empty() || self.permissions.view_only {
+            return;
+        }
+        let slots = self.rack.len();
+        let mut active = self.active;
+        for input in inputs {
+            let throttle = &mut self.rack[active];
+            match input {
+                Input::Action(Action::PreviousSlot) => active = (active + slots - 1) % slots,
+                Input::Action(Action::NextSlot) => active = (active + 1) % slots,
+                Input::Action(action) => throttle.perform(action, connection),
+                Input::Velocity(velocity) if velocity != throttle.target => {
+                    throttle.set_velocity(velocity, connection)
                 }
-                Velocity(v) => throttle.velocity = v,
-                FunctionPressed(f) => {
-                    throttle.functions.insert(f);
+                Input::Velocity(_) => {}
+            }
+        }
+        if active != self.active {
+            self.activate(ctx, active);
+        }
+    }
+
+    /// Buttons sending a command for every slot at once.
+    fn all_throttles(&mut self, ui: &mut Ui) {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        if self.rack.iter().all(Throttle::is_empty) || self.permissions.view_only {
+            return;
+        }
+        let mut sent = None;
+        ui.horizontal(|ui| {
+            if ui.button("Stop all").clicked() {
+                sent = Some(WiMessageType::Velocity(Velocity::STOP));
+            }
+            if ui.button("E-stop all").clicked() {
+                sent = Some(WiMessageType::EmergencyStop);
+            }
+            if ui.button("Release all").clicked() {
+                sent = Some(WiMessageType::RemoveAddress);
+            }
+        });
+        let Some(message_type) = sent else {
+            return;
+        };
+        for throttle in self.rack.iter_mut().filter(|throttle| !throttle.is_empty()) {
+            connection.send(WiMessage::new(Target::All, message_type).with_throttle(throttle.slot));
+            if message_type != WiMessageType::RemoveAddress {
+                // Stopped straight away, or ramping throttles would carry on from where they were
+                throttle.stop_now();
+            }
+        }
+    }
+
+    /// A tab per slot to switch between them quickly.
+    fn slot_tabs(&mut self, ui: &mut Ui) {
+        let mut clicked = None;
+        ui.horizontal(|ui| {
+            for (index, throttle) in self.rack.iter().enumerate() {
+                let text = match throttle.lead() {
+                    Some(lead) => format!("{}: {lead}", throttle.slot),
+                    None => throttle.slot.to_string(),
+                };
+                if ui.selectable_label(index == self.active, text).clicked() {
+                    clicked = Some(index);
                 }
-                FunctionReleased(f) => {
-                    throttle.functions.remove(&f);
+            }
+        });
+        if let Some(index) = clicked {
+            self.activate(ui.ctx(), index);
+        }
+    }
+
+    /// Every slot in its own window, for screens with room for them side by side.
+    fn throttle_windows(&mut self, ctx: &Context) {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        let pressed = ctx.input(|i| i.pointer.press_origin().filter(|_| i.pointer.any_pressed()));
+        for (index, throttle) in self.rack.iter_mut().enumerate() {
+            let mut add = false;
+            let window = Window::new(throttle.title())
+                .id(slot_window_id(throttle.slot))
+                .fixed_size([340.0, 460.0])
+                .show(ctx, |ui| add = throttle.draw(connection, ui));
+            if add {
+                self.state.add_to_slot = Some(index);
+            }
+            // Made active like egui raises windows, by pressing anywhere on them
+            if let (Some(window), Some(pos)) = (window, pressed) {
+                if ctx.layer_id_at(pos) == Some(window.response.layer_id) {
+                    self.active = index;
                 }
-                Direction(d) => throttle.direction = d,
-                Time(t) => self.time = t,
             }
         }
     }
 
+    /// One slot at a time on narrow screens like phones, moving between them by swiping or
+    /// with the arrows. The one shown is the active one.
+    fn mobile_throttle(&mut self, ui: &mut Ui) {
+        let moved = self.swipe(ui.ctx());
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        let slots = self.rack.len();
+        let mut index = self.active.saturating_add_signed(moved).min(slots - 1);
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            let size = Vec2::new(48.0, 48.0);
+            if ui
+                .add_enabled(index > 0, Button::new("◀").min_size(size))
+                .clicked()
+            {
+                index -= 1;
+            }
+            ui.heading(self.rack[index].title());
+            ui.label(format!("{} of {slots}", index + 1));
+            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                if ui
+                    .add_enabled(index + 1 < slots, Button::new("▶").min_size(size))
+                    .clicked()
+                {
+                    index += 1;
+                }
+            });
+        });
+        ui.separator();
+
+        self.active = index;
+        if self.rack[index].draw_mobile(connection, ui) {
+            self.state.add_to_slot = Some(index);
+        }
+    }
+
+    /// Which way the throttles were swiped this frame, 1 for the next one and -1 for the one
+    /// before. Drags that move a slider or scroll the functions aren't swipes.
+    fn swipe(&mut self, ctx: &Context) -> isize {
+        if ctx.memory(|memory| memory.is_anything_being_dragged()) {
+            self.state.swipe_start = None;
+        }
+        let (pressed, released, pos) = ctx.input(|i| {
+            let pressed = i.pointer.press_origin().filter(|_| i.pointer.any_pressed());
+            (pressed, i.pointer.any_released(), i.pointer.interact_pos())
+        });
+        if pressed.is_some() {
+            self.state.swipe_start = pressed;
+        }
+        if !released {
+            return 0;
+        }
+        let (Some(start), Some(end)) = (self.state.swipe_start.take(), pos) else {
+            return 0;
+        };
+        let distance = end - start;
+        if distance.x.abs() < SWIPE_DISTANCE || distance.x.abs() < 2.0 * distance.y.abs() {
+            return 0;
+        }
+        // Swiping left brings in the next throttle from the right
+        match distance.x < 0.0 {
+            true => 1,
+            false => -1,
+        }
+    }
+
+    /// Lists servers found over mDNS, clicking one fills in the URL.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn discovered_servers(&mut self, ui: &mut Ui) {
+        let Some(discovery) = self.discovery.as_mut() else {
+            return;
+        };
+        ui.add_space(10.0);
+        ui.label("Servers on this network:");
+        let mut found = false;
+        for (name, url) in discovery.servers() {
+            found = true;
+            if ui
+                .selectable_label(self.url == *url, format!("{name} ({url})"))
+                .clicked()
+            {
+                self.url = url.clone();
+            }
+        }
+        if !found {
+            ui.weak("Searching...");
+        }
+        // Discovery events don't wake up the UI by themselves
+        ui.ctx()
+            .request_repaint_after(std::time::Duration::from_secs(1));
+    }
+
     fn menu_bar(&mut self, ui: &mut Ui) {
         egui::menu::bar(ui, |ui| {
             egui::widgets::global_dark_light_mode_switch(ui);
@@ -157,19 +753,53 @@ impl App {
                 self.disconnect();
             }
 
+            if self.connection.is_some() && !self.state.connecting && !self.permissions.view_only {
+                ui.separator();
+                let adding = self.state.add_to_slot.is_some();
+                if ui.add(Button::new("Add loco").selected(adding)).clicked() {
+                    self.state.add_to_slot = match adding {
+                        true => None,
+                        false => Some(self.active),
+                    };
+                }
+            }
+
+            ui.separator();
+            let mut slots = self.rack.len();
+            ui.label("Slots:");
+            ui.add(egui::DragValue::new(&mut slots).clamp_range(1..=MAX_SLOTS));
+            if slots != self.rack.len() {
+                self.set_slots(slots);
+            }
+
+            ui.separator();
+            if ui
+                .add(Button::new("Controls").selected(self.controls.show))
+                .clicked()
+            {
+                self.controls.show = !self.controls.show;
+            }
+
             if self.connection.is_some() && !self.state.connecting {
                 ui.separator();
                 if ui
-                    .add(Button::new("New Throttle").selected(self.state.show_new_throttle))
+                    .add(Button::new("About server").selected(self.state.show_about))
                     .clicked()
                 {
-                    self.state.show_new_throttle = !self.state.show_new_throttle;
+                    self.state.show_about = !self.state.show_about;
                 }
             }
 
             ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
                 let dt = NaiveDateTime::from_timestamp_opt(self.time, 0).unwrap();
                 ui.label(format!("Fast Clock: {}", dt.format("%l:%M %p")));
+                if let Some(user) = &self.user {
+                    ui.separator();
+                    match self.permissions.view_only {
+                        true => ui.label(format!("{user} (view only)")),
+                        false => ui.label(user),
+                    };
+                }
             });
         });
     }
@@ -178,6 +808,10 @@ impl App {
 impl eframe::App for App {
     fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| self.menu_bar(ui));
+        self.about_window(ctx);
+        self.alert_window(ctx);
+        self.restore_window(ctx);
+        self.controls.window(ctx);
 
         egui::CentralPanel::default().show(ctx, |ui| {
             if self.state.show_connect {
@@ -192,7 +826,27 @@ impl eframe::App for App {
                                 !self.state.connecting,
                                 TextEdit::singleline(&mut self.url),
                             );
+                            ui.end_row();
+                            ui.label("PIN or token:");
+                            ui.add_enabled(
+                                !self.state.connecting,
+                                TextEdit::singleline(&mut self.token).password(true),
+                            );
+                            ui.end_row();
+                            ui.label("Encoding:");
+                            ui.add_enabled_ui(!self.state.connecting, |ui| {
+                                ui.horizontal(|ui| {
+                                    ui.selectable_value(&mut self.encoding, Encoding::Json, "JSON");
+                                    ui.selectable_value(
+                                        &mut self.encoding,
+                                        Encoding::MsgPack,
+                                        "MessagePack",
+                                    );
+                                });
+                            });
                         });
+                        #[cfg(not(target_arch = "wasm32"))]
+                        self.discovered_servers(ui);
                         ui.add_space(15.0);
                         ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
                             if self.state.connecting {
@@ -206,60 +860,109 @@ impl eframe::App for App {
                             }
                         });
                     });
-            } else if self.state.show_new_throttle {
-                Window::new("New Throttle")
+            } else if let Some(index) = self.state.add_to_slot {
+                Window::new(format!("Add loco to slot {}", self.rack[index].slot))
+                    .id(Id::new("AddLoco"))
                     .collapsible(false)
                     .resizable(false)
                     .show(ctx, |ui| {
                         ui.label("Address:");
                         TextEdit::singleline(&mut self.state.new_address).show(ui);
+                        let number = self.state.new_address.parse::<Address>();
+                        let guessed = match number {
+                            Ok(number) if number >= DEFAULT_SHORT_ADDRESS_LIMIT => {
+                                AddressKind::Long
+                            }
+                            _ => AddressKind::Short,
+                        };
+                        let shown = self.state.new_address_kind.unwrap_or(guessed);
+                        let mut kind = shown;
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut kind, AddressKind::Short, "Short");
+                            ui.radio_value(&mut kind, AddressKind::Long, "Long");
+                        });
+                        if kind != shown {
+                            self.state.new_address_kind = Some(kind);
+                        }
+
+                        let address = match number {
+                            Ok(number) => LocoAddress::new(number, kind).map_err(|e| e.to_string()),
+                            Err(e) => Err(format!("Invalid address: {e}")),
+                        };
+                        if let (Err(e), false) = (&address, self.state.new_address.is_empty()) {
+                            ui.colored_label(ui.visuals().error_fg_color, e);
+                        }
                         ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
                             if ui.button("Add").clicked() {
-                                if let Ok(address) = self.state.new_address.parse::<Address>() {
-                                    let sender = &mut self.connection.as_mut().unwrap().ws_sender;
-                                    let message =
-                                        WiMessage::new(address, WiMessageType::AddAddress);
-                                    let message = serde_json::to_string(&message).unwrap();
-                                    sender.send(WsMessage::Text(message));
-
-                                    // TODO: Confirm to add when we get a response from the server
-                                    self.throttles.insert(address, Throttle::new(address));
-
-                                    self.state.show_new_throttle = false;
+                                if let Ok(address) = address {
+                                    self.add_loco(index, address);
+                                    self.state.add_to_slot = None;
                                     self.state.new_address = String::new();
+                                    self.state.new_address_kind = None;
                                 } else {
                                     info!("Cannot parse address: {}", self.state.new_address);
                                 }
                             }
                             if ui.button("Cancel").clicked() {
                                 self.state.new_address = String::default();
-                                self.state.show_new_throttle = false;
+                                self.state.new_address_kind = None;
+                                self.state.add_to_slot = None;
                             }
                         });
                     });
             }
 
+            let mobile = ctx.screen_rect().width() < MOBILE_WIDTH;
             ui.heading("Throttles");
+            self.all_throttles(ui);
+            if self.connection.is_some() && !mobile {
+                self.slot_tabs(ui);
+            }
 
             if let Some(connection) = self.connection.borrow_mut() {
-                for throttle in self.throttles.values_mut() {
-                    Window::new(throttle.address.to_string())
-                        .fixed_size([340.0, 400.0])
-                        .show(ctx, |ui| {
-                            throttle.draw(connection, ui);
-                        });
+                let seconds = ctx.input(|i| i.stable_dt);
+                for throttle in &mut self.rack {
+                    if throttle.tick(seconds, connection) {
+                        ctx.request_repaint_after(std::time::Duration::from_millis(50));
+                    }
                 }
             }
 
+            match mobile {
+                true => self.mobile_throttle(ui),
+                false => self.throttle_windows(ctx),
+            }
+
             ui.with_layout(Layout::bottom_up(Align::LEFT), |ui| {
                 egui::warn_if_debug_build(ui);
             });
         });
 
+        self.apply_controls(ctx);
         self.handle_messages(ctx);
+        self.toasts.show(ctx);
     }
 
     fn save(&mut self, storage: &mut dyn Storage) {
         eframe::set_value(storage, eframe::APP_KEY, &self.uuid);
+        let held = self.held();
+        let session = Session {
+            url: Some(self.url.clone()),
+            encoding: self.encoding,
+            slots: self.rack.len(),
+            rack: match held.is_empty() {
+                true => self.restore.clone(),
+                false => held,
+            },
+        };
+        eframe::set_value(storage, SESSION_KEY, &session);
+        eframe::set_value(storage, CONTROLS_KEY, &self.controls.bindings);
+        self.remember_momentum();
+        eframe::set_value(storage, MOMENTUM_KEY, &self.momentum);
     }
 }
+
+/// A slot's window keeps its id, and its place, as its title changes.
+fn slot_window_id(slot: ThrottleId) -> Id {
+    Id::new(("Slot", slot))
+}
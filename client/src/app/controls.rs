@@ -0,0 +1,345 @@
+#[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+use crate::app::gamepad::{Gamepad, GamepadBindings, GamepadEvent};
+use eframe::egui;
+use eframe::egui::{Context, Event, Grid, Key, Window};
+use jmri_throttle_rs::message::{Function, Velocity};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Something a key or gamepad button does to the focused throttle, or which one that is.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Action {
+    /// Changes the speed by this many steps.
+    Speed(i32),
+    Stop,
+    EmergencyStop,
+    Forward,
+    Reverse,
+    /// Presses the function, toggling it like its button.
+    Function(Function),
+    PreviousSlot,
+    NextSlot,
+}
+
+impl Action {
+    /// Every action, in the order they're listed on the bindings screen.
+    pub fn all() -> Vec<Action> {
+        let mut actions = vec![
+            Action::Speed(1),
+            Action::Speed(-1),
+            Action::Speed(10),
+            Action::Speed(-10),
+            Action::Stop,
+            Action::EmergencyStop,
+            Action::Forward,
+            Action::Reverse,
+            Action::PreviousSlot,
+            Action::NextSlot,
+        ];
+        actions.extend(
+            (0..=12)
+                .filter_map(|f| Function::try_from(f).ok())
+                .map(Action::Function),
+        );
+        actions
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Speed(delta) => write!(f, "Speed {delta:+}"),
+            Action::Stop => write!(f, "Stop"),
+            Action::EmergencyStop => write!(f, "E-stop"),
+            Action::Forward => write!(f, "Forward"),
+            Action::Reverse => write!(f, "Reverse"),
+            Action::Function(function) => write!(f, "F{function}"),
+            Action::PreviousSlot => write!(f, "Previous slot"),
+            Action::NextSlot => write!(f, "Next slot"),
+        }
+    }
+}
+
+/// What the controls want done to the focused throttle.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Input {
+    Action(Action),
+    /// Set by a gamepad axis.
+    #[cfg_attr(
+        not(all(feature = "gamepad", not(target_arch = "wasm32"))),
+        allow(dead_code)
+    )]
+    Velocity(Velocity),
+}
+
+impl Input {
+    /// The speed for a speed axis at `value`, from -1 to 1. Pushed all the way forward is full
+    /// speed, anything at or behind the centre stops.
+    #[cfg_attr(
+        not(all(feature = "gamepad", not(target_arch = "wasm32"))),
+        allow(dead_code)
+    )]
+    pub fn from_axis(value: f32) -> Self {
+        let steps = value.max(0.0) * f32::from(Velocity::MAX.get());
+        Input::Velocity(Velocity::saturating(steps.round() as i32))
+    }
+}
+
+/// Keyboard and gamepad bindings, saved between runs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Bindings {
+    pub keys: BTreeMap<Action, Key>,
+    #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+    pub gamepad: GamepadBindings,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut keys = BTreeMap::from([
+            (Action::Speed(1), Key::ArrowUp),
+            (Action::Speed(-1), Key::ArrowDown),
+            (Action::Speed(10), Key::PageUp),
+            (Action::Speed(-10), Key::PageDown),
+            (Action::Stop, Key::Space),
+            (Action::EmergencyStop, Key::Escape),
+            (Action::Forward, Key::ArrowRight),
+            (Action::Reverse, Key::ArrowLeft),
+            (Action::PreviousSlot, Key::Minus),
+            (Action::NextSlot, Key::PlusEquals),
+        ]);
+        // F10 and up are left unbound, browsers keep the function keys for themselves
+        let digits = [
+            Key::Num0,
+            Key::Num1,
+            Key::Num2,
+            Key::Num3,
+            Key::Num4,
+            Key::Num5,
+            Key::Num6,
+            Key::Num7,
+            Key::Num8,
+            Key::Num9,
+        ];
+        for (f, key) in digits.into_iter().enumerate() {
+            if let Ok(function) = Function::try_from(f as u8) {
+                keys.insert(Action::Function(function), key);
+            }
+        }
+        Self {
+            keys,
+            #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+            gamepad: GamepadBindings::default(),
+        }
+    }
+}
+
+impl Bindings {
+    /// Binds a key to an action, taking it away from any other action.
+    fn bind_key(&mut self, action: Action, key: Key) {
+        self.keys.retain(|_, bound| *bound != key);
+        self.keys.insert(action, key);
+    }
+}
+
+/// The binding the next key press or gamepad input goes to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Rebinding {
+    Action(Action),
+    #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+    SpeedAxis,
+}
+
+/// Turns key presses and gamepad input into commands for the focused throttle, and shows the
+/// screen to change what's bound to what.
+pub struct Controls {
+    pub bindings: Bindings,
+    pub show: bool,
+    rebinding: Option<Rebinding>,
+    #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+    gamepad: Option<Gamepad>,
+}
+
+impl Controls {
+    pub fn new(bindings: Bindings) -> Self {
+        Self {
+            bindings,
+            show: false,
+            rebinding: None,
+            #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+            gamepad: Gamepad::start(),
+        }
+    }
+
+    /// Input since the last frame. Keys are ignored while a text field has focus, and
+    /// everything goes to the binding being changed if there is one.
+    pub fn poll(&mut self, ctx: &Context) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        let typing = ctx.wants_keyboard_input();
+        let keys: Vec<(Key, bool)> = ctx.input(|i| {
+            i.events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Key {
+                        key,
+                        pressed: true,
+                        repeat,
+                        modifiers,
+                    } if !modifiers.command && !modifiers.alt => Some((*key, *repeat)),
+                    _ => None,
+                })
+                .collect()
+        });
+        for (key, repeat) in keys {
+            if let Some(Rebinding::Action(action)) = self.rebinding {
+                self.bindings.bind_key(action, key);
+                self.rebinding = None;
+                continue;
+            }
+            if typing || self.rebinding.is_some() {
+                continue;
+            }
+            let bound = self.bindings.keys.iter().find(|(_, bound)| **bound == key);
+            match bound {
+                // Holding a speed key keeps changing the speed, anything else happens once
+                Some((action @ Action::Speed(_), _)) => inputs.push(Input::Action(*action)),
+                Some((action, _)) if !repeat => inputs.push(Input::Action(*action)),
+                _ => {}
+            }
+        }
+
+        #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+        if let Some(gamepad) = self.gamepad.as_mut() {
+            for event in gamepad.poll() {
+                let gamepad_bindings = &mut self.bindings.gamepad;
+                match (self.rebinding, event) {
+                    (Some(Rebinding::Action(action)), GamepadEvent::Pressed(button)) => {
+                        gamepad_bindings.bind_button(action, button);
+                        self.rebinding = None;
+                    }
+                    (Some(Rebinding::SpeedAxis), GamepadEvent::Moved(axis, value))
+                        if value.abs() > 0.5 =>
+                    {
+                        gamepad_bindings.speed_axis = Some(axis);
+                        self.rebinding = None;
+                    }
+                    (Some(_), _) => {}
+                    (None, event) => inputs.extend(gamepad_bindings.input(event)),
+                }
+            }
+            // Gamepad events don't wake up the UI by themselves
+            ctx.request_repaint_after(std::time::Duration::from_millis(50));
+        }
+        inputs
+    }
+
+    /// The bindings screen, each binding is changed by clicking it and then pressing the new
+    /// key or button.
+    pub fn window(&mut self, ctx: &Context) {
+        let mut show = self.show;
+        Window::new("Controls")
+            .open(&mut show)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Applied to the active slot.");
+                ui.add_space(5.0);
+                egui::ScrollArea::vertical()
+                    .max_height(400.0)
+                    .show(ui, |ui| {
+                        Grid::new("ControlsGrid")
+                            .num_columns(3)
+                            .striped(true)
+                            .show(ui, |ui| self.bindings_grid(ui));
+                    });
+                ui.add_space(5.0);
+                ui.horizontal(|ui| {
+                    if ui.button("Reset to defaults").clicked() {
+                        self.bindings = Bindings::default();
+                        self.rebinding = None;
+                    }
+                    if self.rebinding.is_some() && ui.button("Cancel").clicked() {
+                        self.rebinding = None;
+                    }
+                });
+            });
+        if !show {
+            self.rebinding = None;
+        }
+        self.show = show;
+    }
+
+    fn bindings_grid(&mut self, ui: &mut egui::Ui) {
+        ui.strong("Action");
+        ui.strong("Key");
+        #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+        ui.strong("Gamepad");
+        ui.end_row();
+
+        for action in Action::all() {
+            ui.label(action.to_string());
+            let waiting = self.rebinding == Some(Rebinding::Action(action));
+            let key = match (waiting, self.bindings.keys.get(&action)) {
+                (true, _) => "Press a key...",
+                (false, Some(key)) => key.name(),
+                (false, None) => "None",
+            };
+            if ui.selectable_label(waiting, key).clicked() {
+                self.rebinding = Some(Rebinding::Action(action));
+            }
+            #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+            {
+                let button = match (waiting, self.bindings.gamepad.button(action)) {
+                    (true, _) => "Press a button...".to_string(),
+                    (false, Some(button)) => format!("{button:?}"),
+                    (false, None) => "None".to_string(),
+                };
+                if ui.selectable_label(waiting, button).clicked() {
+                    self.rebinding = Some(Rebinding::Action(action));
+                }
+            }
+            if ui.small_button("Clear").clicked() {
+                self.bindings.keys.remove(&action);
+                #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+                self.bindings.gamepad.unbind_button(action);
+            }
+            ui.end_row();
+        }
+
+        #[cfg(all(feature = "gamepad", not(target_arch = "wasm32")))]
+        {
+            ui.label("Speed axis");
+            ui.label("");
+            let waiting = self.rebinding == Some(Rebinding::SpeedAxis);
+            let axis = match (waiting, self.bindings.gamepad.speed_axis) {
+                (true, _) => "Move an axis...".to_string(),
+                (false, Some(axis)) => format!("{axis:?}"),
+                (false, None) => "None".to_string(),
+            };
+            if ui.selectable_label(waiting, axis).clicked() {
+                self.rebinding = Some(Rebinding::SpeedAxis);
+            }
+            if ui.small_button("Clear").clicked() {
+                self.bindings.gamepad.speed_axis = None;
+            }
+            ui.end_row();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_axis() {
+        let velocity = |speed| Input::Velocity(Velocity::try_from(speed).unwrap());
+        assert_eq!(Input::from_axis(1.0), Input::Velocity(Velocity::MAX));
+        assert_eq!(Input::from_axis(0.5), velocity(63));
+        assert_eq!(Input::from_axis(0.0), Input::Velocity(Velocity::STOP));
+        assert_eq!(Input::from_axis(-1.0), Input::Velocity(Velocity::STOP));
+        // Past the end, as some sticks report a little over 1
+        assert_eq!(Input::from_axis(1.05), Input::Velocity(Velocity::MAX));
+    }
+}
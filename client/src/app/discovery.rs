@@ -0,0 +1,75 @@
+use jmri_throttle_rs::protocol::BRIDGE_SERVICE;
+use log::{error, info};
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Browses for servers advertising themselves over mDNS. Only on native builds, as browsers
+/// can't do mDNS.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    // Full service name to name and URL, sorted so the list doesn't jump around
+    servers: BTreeMap<String, (String, String)>,
+}
+
+impl Discovery {
+    pub fn start() -> Option<Self> {
+        let daemon = ServiceDaemon::new()
+            .map_err(|e| error!("Failed to start mDNS: {e}"))
+            .ok()?;
+        let events = daemon
+            .browse(BRIDGE_SERVICE)
+            .map_err(|e| error!("Failed to browse for servers: {e}"))
+            .ok()?;
+        Some(Self {
+            daemon,
+            events,
+            servers: BTreeMap::new(),
+        })
+    }
+
+    /// The names and URLs of every server found so far.
+    pub fn servers(&mut self) -> impl Iterator<Item = &(String, String)> {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    let addresses = info.get_addresses();
+                    let Some(ip) = addresses
+                        .iter()
+                        .find(|ip| ip.is_ipv4())
+                        .or_else(|| addresses.iter().next())
+                    else {
+                        continue;
+                    };
+                    let host = match ip {
+                        IpAddr::V4(ip) => ip.to_string(),
+                        IpAddr::V6(ip) => format!("[{ip}]"),
+                    };
+                    let path = info.get_property_val_str("path").unwrap_or("/ws");
+                    let scheme = info.get_property_val_str("scheme").unwrap_or("ws");
+                    let url = format!("{scheme}://{host}:{}{path}", info.get_port());
+                    let name = info
+                        .get_fullname()
+                        .trim_end_matches(BRIDGE_SERVICE)
+                        .trim_end_matches('.')
+                        .to_string();
+                    info!("Discovered server '{name}' at {url}");
+                    self.servers
+                        .insert(info.get_fullname().to_string(), (name, url));
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    self.servers.remove(&fullname);
+                }
+                _ => {}
+            }
+        }
+        self.servers.values()
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
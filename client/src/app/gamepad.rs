@@ -0,0 +1,117 @@
+use crate::app::controls::{Action, Input};
+use gilrs::{Axis, Button, EventType, Gilrs};
+use jmri_throttle_rs::message::Function;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Which gamepad buttons do what, and the axis setting the speed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GamepadBindings {
+    pub buttons: HashMap<Button, Action>,
+    /// Sets the speed, see [Input::from_axis].
+    pub speed_axis: Option<Axis>,
+}
+
+impl Default for GamepadBindings {
+    fn default() -> Self {
+        let mut buttons = HashMap::from([
+            (Button::DPadUp, Action::Speed(1)),
+            (Button::DPadDown, Action::Speed(-1)),
+            (Button::RightTrigger, Action::Speed(10)),
+            (Button::LeftTrigger, Action::Speed(-10)),
+            (Button::South, Action::Stop),
+            (Button::Select, Action::EmergencyStop),
+            (Button::DPadRight, Action::Forward),
+            (Button::DPadLeft, Action::Reverse),
+            (Button::LeftTrigger2, Action::PreviousSlot),
+            (Button::RightTrigger2, Action::NextSlot),
+        ]);
+        // Lights and horn
+        for (button, f) in [(Button::North, 0), (Button::West, 2)] {
+            if let Ok(function) = Function::try_from(f) {
+                buttons.insert(button, Action::Function(function));
+            }
+        }
+        Self {
+            buttons,
+            speed_axis: Some(Axis::LeftStickY),
+        }
+    }
+}
+
+impl GamepadBindings {
+    pub fn button(&self, action: Action) -> Option<Button> {
+        self.buttons
+            .iter()
+            .find(|(_, bound)| **bound == action)
+            .map(|(button, _)| *button)
+    }
+
+    /// Binds a button to an action in place of the action's current button.
+    pub fn bind_button(&mut self, action: Action, button: Button) {
+        self.unbind_button(action);
+        self.buttons.insert(button, action);
+    }
+
+    pub fn unbind_button(&mut self, action: Action) {
+        self.buttons.retain(|_, bound| *bound != action);
+    }
+
+    pub fn input(&self, event: GamepadEvent) -> Option<Input> {
+        match event {
+            GamepadEvent::Pressed(button) => self.buttons.get(&button).copied().map(Input::Action),
+            GamepadEvent::Moved(axis, value) if Some(axis) == self.speed_axis => {
+                Some(Input::from_axis(value))
+            }
+            GamepadEvent::Moved(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GamepadEvent {
+    Pressed(Button),
+    /// An axis moved to a value from -1 to 1.
+    Moved(Axis, f32),
+}
+
+/// Reads every connected gamepad and joystick. Only on native builds with the `gamepad`
+/// feature.
+pub struct Gamepad {
+    gilrs: Gilrs,
+}
+
+impl Gamepad {
+    pub fn start() -> Option<Self> {
+        let gilrs = Gilrs::new()
+            .map_err(|e| error!("Failed to start gamepad support: {e}"))
+            .ok()?;
+        for (_, gamepad) in gilrs.gamepads() {
+            info!("Found gamepad '{}'", gamepad.name());
+        }
+        Some(Self { gilrs })
+    }
+
+    /// Events since the last poll.
+    pub fn poll(&mut self) -> Vec<GamepadEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => events.push(GamepadEvent::Pressed(button)),
+                EventType::AxisChanged(axis, value, _) => {
+                    events.push(GamepadEvent::Moved(axis, value))
+                }
+                EventType::Connected => {
+                    info!(
+                        "Gamepad '{}' connected",
+                        self.gilrs.gamepad(event.id).name()
+                    )
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
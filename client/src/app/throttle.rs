@@ -1,133 +1,485 @@
+use crate::app::controls::Action;
 use crate::app::WsConnection;
 use eframe::egui;
-use eframe::egui::{Button, Ui, Vec2};
-use jmri_throttle_rs::message::{Address, Direction, Function, Velocity, WiMessage, WiMessageType};
-use std::collections::HashSet;
+use eframe::egui::{Button, DragValue, ProgressBar, Ui, Vec2};
+use jmri_throttle_rs::message::{
+    Direction, Function, LocoAddress, Target, ThrottleId, Velocity, WiMessage, WiMessageType,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
 
 static BUTTON_SIZE: Vec2 = Vec2::new(50.0, 50.0);
+static MOBILE_BUTTON_SIZE: Vec2 = Vec2::new(72.0, 72.0);
 
+/// Longest frame a ramp moves on by, so it doesn't jump after the UI has been idle.
+const MAX_TICK_SECONDS: f32 = 0.1;
+
+/// How many of the speeds last sent are taken for JMRI echoing them back, rather than for
+/// someone else changing the speed.
+const ECHOED_VELOCITIES: usize = 8;
+
+/// How quickly a loco changes speed when driving with momentum, in speed steps per second.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct Momentum {
+    pub enabled: bool,
+    pub acceleration: f32,
+    /// Slowing down with the brake off.
+    pub deceleration: f32,
+    /// Added to the deceleration with the brake fully on.
+    pub braking: f32,
+}
+
+impl Default for Momentum {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            acceleration: 10.0,
+            deceleration: 5.0,
+            braking: 40.0,
+        }
+    }
+}
+
+/// One slot of the throttle rack, driving its locos on a multi-throttle of its own.
 pub struct Throttle {
+    pub slot: ThrottleId,
+    /// The lead loco first, any others run with it as a consist.
+    pub addresses: Vec<LocoAddress>,
+    /// The speed last sent to or reported by JMRI.
     pub velocity: Velocity,
-    pub address: Address,
+    /// The speed asked for, reached straight away unless momentum is on.
+    pub target: Velocity,
+    pub momentum: Momentum,
+    /// From 0 for off to 1 for full.
+    brake: f32,
+    /// The speed in fractional steps while ramping.
+    ramp: f32,
+    /// The speeds last sent, newest last.
+    sent: VecDeque<Velocity>,
     pub functions: HashSet<Function>,
     pub direction: Direction,
 }
 
 impl Throttle {
-    pub fn new(address: Address) -> Throttle {
+    pub fn new(slot: ThrottleId) -> Throttle {
         Self {
-            address,
-            velocity: 0,
+            slot,
+            addresses: Vec::new(),
+            velocity: Velocity::STOP,
+            target: Velocity::STOP,
+            momentum: Momentum::default(),
+            brake: 0.0,
+            ramp: 0.0,
+            sent: VecDeque::new(),
             functions: HashSet::new(),
             direction: Direction::default(),
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn lead(&self) -> Option<LocoAddress> {
+        self.addresses.first().copied()
+    }
+
+    /// The slot's letter and its locos, e.g. `B: S3 + L4014`.
+    pub fn title(&self) -> String {
+        let addresses: Vec<String> = self.addresses.iter().map(|a| a.to_string()).collect();
+        match addresses.is_empty() {
+            true => format!("{}: empty", self.slot),
+            false => format!("{}: {}", self.slot, addresses.join(" + ")),
+        }
+    }
+
+    /// Adds a loco, taking on its momentum settings if it's the first.
+    pub fn add(&mut self, address: LocoAddress, momentum: Momentum) {
+        if self.is_empty() {
+            self.momentum = momentum;
+        }
+        if !self.addresses.contains(&address) {
+            self.addresses.push(address);
+        }
+    }
+
+    /// Removes a loco, starting afresh once the slot is empty. Returns whether it was there.
+    pub fn remove(&mut self, address: LocoAddress) -> bool {
+        let held = self.addresses.contains(&address);
+        self.addresses.retain(|held| *held != address);
+        if held && self.is_empty() {
+            *self = Self::new(self.slot);
+        }
+        held
+    }
+
+    /// The speed being ramped to, the target cut by however far the brake is on.
+    fn ramp_target(&self) -> f32 {
+        (f32::from(self.target.get()) * (1.0 - self.brake)).round()
+    }
+
+    fn ramping(&self) -> bool {
+        self.momentum.enabled && self.ramp != self.ramp_target()
+    }
+
+    /// Applies an update from the server.
+    pub fn apply(&mut self, message_type: WiMessageType) {
+        match message_type {
+            // JMRI echoing the speeds sent on the way to the target
+            WiMessageType::Velocity(velocity) if self.sent.contains(&velocity) => {}
+            // Another throttle or JMRI itself changing the speed, which ends any ramp
+            WiMessageType::Velocity(velocity) => {
+                self.sent.clear();
+                self.velocity = velocity;
+                self.target = velocity;
+                self.ramp = f32::from(velocity.get());
+            }
+            WiMessageType::EmergencyStop => self.stop_now(),
+            WiMessageType::FunctionPressed(function) => {
+                self.functions.insert(function);
+            }
+            WiMessageType::FunctionReleased(function) => {
+                self.functions.remove(&function);
+            }
+            WiMessageType::Direction(direction) => self.direction = direction,
+            WiMessageType::AddAddress
+            | WiMessageType::RemoveAddress
+            | WiMessageType::Time(..)
+            | WiMessageType::SpeedStepMode(_) => {}
+        }
+    }
+
+    /// A command for every loco in the slot.
     fn message(&self, message_type: WiMessageType) -> WiMessage {
-        WiMessage::new(self.address, message_type)
+        WiMessage::new(Target::All, message_type).with_throttle(self.slot)
     }
 
-    fn adjust_velocity(&mut self, delta: Velocity, connection: &mut WsConnection) {
-        self.velocity += delta;
-        self.velocity = self.velocity.clamp(0, 126);
-        connection.send(self.message(WiMessageType::Velocity(self.velocity)));
+    /// Stops without momentum, for stops that were sent some other way.
+    pub fn stop_now(&mut self) {
+        self.velocity = Velocity::STOP;
+        self.target = Velocity::STOP;
+        self.ramp = 0.0;
     }
 
-    pub fn draw(&mut self, connection: &mut WsConnection, ui: &mut Ui) {
-        ui.add_space(30.0);
-        ui.horizontal_top(|ui| {
-            // ui.add_space(15.0);
-            if ui
-                .add(
-                    egui::Slider::new(&mut self.velocity, 0..=126)
-                        .vertical()
-                        .integer()
-                        .trailing_fill(true),
-                )
-                .changed()
-            {
-                connection.send(self.message(WiMessageType::Velocity(self.velocity)));
+    fn send_velocity(&mut self, velocity: Velocity, connection: &mut WsConnection) {
+        self.ramp = f32::from(velocity.get());
+        self.send_step(velocity, connection);
+    }
+
+    /// Sends a speed, remembering it to tell JMRI's echo apart from other changes.
+    fn send_step(&mut self, velocity: Velocity, connection: &mut WsConnection) {
+        self.velocity = velocity;
+        if self.sent.len() == ECHOED_VELOCITIES {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(velocity);
+        connection.send(self.message(WiMessageType::Velocity(velocity)));
+    }
+
+    /// Sets the speed to drive at, which is ramped up or down to with momentum.
+    pub fn set_velocity(&mut self, velocity: Velocity, connection: &mut WsConnection) {
+        self.target = velocity;
+        if !self.momentum.enabled {
+            self.send_velocity(velocity, connection);
+        }
+    }
+
+    /// Moves the speed towards the target by however much momentum allows in the time since
+    /// the last frame, returning whether it's still on its way.
+    pub fn tick(&mut self, seconds: f32, connection: &mut WsConnection) -> bool {
+        if !self.ramping() {
+            return false;
+        }
+        let seconds = seconds.min(MAX_TICK_SECONDS);
+        let target = self.ramp_target();
+        self.ramp = if self.ramp < target {
+            (self.ramp + self.momentum.acceleration * seconds).min(target)
+        } else {
+            let rate = self.momentum.deceleration + self.brake * self.momentum.braking;
+            (self.ramp - rate * seconds).max(target)
+        };
+        let velocity = Velocity::saturating(self.ramp.round() as i32);
+        if velocity != self.velocity {
+            self.send_step(velocity, connection);
+        }
+        self.ramping()
+    }
+
+    /// Does what a button, key or gamepad button bound to the action does.
+    pub fn perform(&mut self, action: Action, connection: &mut WsConnection) {
+        let Some(lead) = self.lead() else {
+            return;
+        };
+        match action {
+            Action::Speed(delta) => {
+                let velocity = Velocity::saturating(i32::from(self.target.get()) + delta);
+                self.set_velocity(velocity, connection);
             }
+            Action::Stop => self.set_velocity(Velocity::STOP, connection),
+            Action::EmergencyStop => {
+                self.stop_now();
+                connection.send(self.message(WiMessageType::EmergencyStop));
+            }
+            Action::Forward | Action::Reverse => {
+                self.direction = match action {
+                    Action::Forward => Direction::Forward,
+                    _ => Direction::Reverse,
+                };
+                connection.send(self.message(WiMessageType::Direction(self.direction)));
+            }
+            // Only the lead loco's lights and horn, not the whole consist's
+            Action::Function(function) => {
+                let press = WiMessage::new(lead, WiMessageType::FunctionPressed(function));
+                connection.send(press.with_throttle(self.slot))
+            }
+            // Switching slots is up to the rack
+            Action::PreviousSlot | Action::NextSlot => {}
+        }
+    }
+
+    /// Draws the slot, returning whether a loco should be added to it.
+    pub fn draw(&mut self, connection: &mut WsConnection, ui: &mut Ui) -> bool {
+        let add = self.consist(connection, ui, BUTTON_SIZE.y / 2.0);
+        if self.is_empty() {
+            return add;
+        }
+        ui.add_space(20.0);
+        ui.horizontal_top(|ui| {
+            self.speed_slider(connection, ui);
 
             ui.add_space(15.0);
-            egui::Grid::new(format!("{}ThrottleColumns", self.address))
+            egui::Grid::new(format!("{}ThrottleColumns", self.slot))
                 .num_columns(2)
                 .spacing([10.0, 10.0])
                 .show(ui, |ui| {
-                    if ui.add(Button::new("-1").min_size(BUTTON_SIZE)).clicked() {
-                        self.adjust_velocity(-1, connection);
-                    }
-                    if ui.add(Button::new("+1").min_size(BUTTON_SIZE)).clicked() {
-                        self.adjust_velocity(1, connection);
-                    }
-                    ui.end_row();
-                    if ui.add(Button::new("-10").min_size(BUTTON_SIZE)).clicked() {
-                        self.adjust_velocity(-10, connection);
-                    }
-                    if ui.add(Button::new("+10").min_size(BUTTON_SIZE)).clicked() {
-                        self.adjust_velocity(10, connection);
-                    }
-                    ui.end_row();
-                    if ui
-                        .add(
-                            Button::new("Stop")
-                                .selected(self.velocity == 0)
-                                .min_size(BUTTON_SIZE),
-                        )
-                        .clicked()
-                    {
-                        self.velocity = 0;
-                        connection.send(self.message(WiMessageType::Velocity(0)));
-                    }
-                    if ui
-                        .add(
-                            Button::new("E-stop")
-                                .selected(self.velocity < 0)
-                                .min_size(BUTTON_SIZE),
-                        )
-                        .clicked()
-                    {
-                        self.velocity = -1;
-                        connection.send(self.message(WiMessageType::Velocity(-1)));
-                    }
+                    self.speed_buttons(connection, ui, BUTTON_SIZE);
+                    self.stop_buttons(connection, ui, BUTTON_SIZE);
                     ui.end_row();
                 });
 
+            self.direction_buttons(connection, ui);
+        });
+
+        self.momentum_status(ui);
+
+        ui.separator();
+
+        ui.horizontal_wrapped(|ui| self.function_buttons(connection, ui, BUTTON_SIZE));
+
+        ui.separator();
+
+        egui::CollapsingHeader::new("Momentum")
+            .id_source(format!("{}Momentum", self.slot))
+            .show(ui, |ui| self.momentum_settings(connection, ui));
+
+        ui.separator();
+
+        if ui.button("Release").clicked() {
+            connection.send(self.message(WiMessageType::RemoveAddress));
+        }
+        add
+    }
+
+    /// One throttle filling a phone screen, with everything big enough to hit with a thumb and
+    /// the functions scrolling underneath. Returns whether a loco should be added.
+    pub fn draw_mobile(&mut self, connection: &mut WsConnection, ui: &mut Ui) -> bool {
+        let add = self.consist(connection, ui, MOBILE_BUTTON_SIZE.y / 2.0);
+        if self.is_empty() {
+            return add;
+        }
+        ui.add_space(10.0);
+        let slider_length = (ui.available_height() * 0.4).max(150.0);
+        ui.columns(2, |columns| {
+            columns[0].vertical_centered(|ui| {
+                ui.spacing_mut().slider_width = slider_length;
+                ui.spacing_mut().interact_size.y = MOBILE_BUTTON_SIZE.y / 2.0;
+                self.speed_slider(connection, ui);
+            });
+            let ui = &mut columns[1];
+            let size = Vec2::new(ui.available_width(), MOBILE_BUTTON_SIZE.y);
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| self.direction_buttons(connection, ui));
+                ui.add_space(10.0);
+                self.stop_buttons(connection, ui, size);
+                ui.add_space(10.0);
+                let half = Vec2::new((size.x - ui.spacing().item_spacing.x) / 2.0, size.y);
+                egui::Grid::new(format!("{}MobileSpeed", self.slot))
+                    .num_columns(2)
+                    .show(ui, |ui| self.speed_buttons(connection, ui, half));
+            });
+        });
+
+        self.momentum_status(ui);
+
+        ui.separator();
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.horizontal_wrapped(|ui| self.function_buttons(connection, ui, MOBILE_BUTTON_SIZE));
+
+            ui.separator();
+
+            egui::CollapsingHeader::new("Momentum")
+                .id_source(format!("{}Momentum", self.slot))
+                .show(ui, |ui| self.momentum_settings(connection, ui));
+
+            ui.separator();
+
             if ui
-                .selectable_value(&mut self.direction, Direction::Reverse, "Reverse")
+                .add(Button::new("Release").min_size(MOBILE_BUTTON_SIZE))
                 .clicked()
             {
-                connection.send(self.message(WiMessageType::Direction(Direction::Reverse)));
+                connection.send(self.message(WiMessageType::RemoveAddress));
+            }
+        });
+        add
+    }
+
+    /// The slot's locos, each with a button to release it, and a button to add another.
+    /// Returns whether that was clicked.
+    fn consist(&mut self, connection: &mut WsConnection, ui: &mut Ui, height: f32) -> bool {
+        let mut add = false;
+        ui.horizontal_wrapped(|ui| {
+            let size = Vec2::new(height, height);
+            for address in &self.addresses {
+                ui.strong(address.to_string());
+                if ui.add(Button::new("✖").min_size(size)).clicked() {
+                    let release = WiMessage::new(*address, WiMessageType::RemoveAddress);
+                    connection.send(release.with_throttle(self.slot));
+                }
             }
+            let label = match self.is_empty() {
+                true => "Add loco",
+                false => "Add to consist",
+            };
+            add = ui.add(Button::new(label).min_size(size)).clicked();
+        });
+        add
+    }
+
+    fn speed_slider(&mut self, connection: &mut WsConnection, ui: &mut Ui) {
+        let mut velocity = self.target.get();
+        if ui
+            .add(
+                egui::Slider::new(&mut velocity, 0..=Velocity::MAX.get())
+                    .vertical()
+                    .integer()
+                    .trailing_fill(true),
+            )
+            .changed()
+        {
+            self.set_velocity(Velocity::saturating(velocity.into()), connection);
+        }
+    }
+
+    /// The -1/+1 and -10/+10 rows of a two column grid.
+    fn speed_buttons(&mut self, connection: &mut WsConnection, ui: &mut Ui, size: Vec2) {
+        for steps in [1, 10] {
             if ui
-                .selectable_value(&mut self.direction, Direction::Forward, "Forward")
+                .add(Button::new(format!("-{steps}")).min_size(size))
                 .clicked()
             {
-                connection.send(self.message(WiMessageType::Direction(Direction::Forward)));
+                self.perform(Action::Speed(-steps), connection);
             }
-        });
+            if ui
+                .add(Button::new(format!("+{steps}")).min_size(size))
+                .clicked()
+            {
+                self.perform(Action::Speed(steps), connection);
+            }
+            ui.end_row();
+        }
+    }
 
-        ui.separator();
+    fn stop_buttons(&mut self, connection: &mut WsConnection, ui: &mut Ui, size: Vec2) {
+        if ui
+            .add(
+                Button::new("Stop")
+                    .selected(self.velocity == Velocity::STOP)
+                    .min_size(size),
+            )
+            .clicked()
+        {
+            self.perform(Action::Stop, connection);
+        }
+        if ui.add(Button::new("E-stop").min_size(size)).clicked() {
+            self.perform(Action::EmergencyStop, connection);
+        }
+    }
 
-        ui.horizontal_wrapped(|ui| {
-            for f in 0..=28 {
-                if ui
-                    .add(
-                        Button::new(format!("F{f}"))
-                            .min_size(BUTTON_SIZE)
-                            .selected(self.functions.contains(&f)),
-                    )
-                    .clicked()
-                {
-                    connection.send(self.message(WiMessageType::FunctionPressed(f)))
-                }
-            }
+    fn direction_buttons(&mut self, connection: &mut WsConnection, ui: &mut Ui) {
+        if ui
+            .selectable_value(&mut self.direction, Direction::Reverse, "Reverse")
+            .clicked()
+        {
+            self.perform(Action::Reverse, connection);
+        }
+        if ui
+            .selectable_value(&mut self.direction, Direction::Forward, "Forward")
+            .clicked()
+        {
+            self.perform(Action::Forward, connection);
+        }
+    }
+
+    /// The speed against the target and the brake, when driving with momentum.
+    fn momentum_status(&mut self, ui: &mut Ui) {
+        if !self.momentum.enabled {
+            return;
+        }
+        ui.add_space(10.0);
+        let actual = f32::from(self.velocity.get()) / f32::from(Velocity::MAX.get());
+        ui.add(
+            ProgressBar::new(actual).text(format!("Speed {} of {}", self.velocity, self.target)),
+        );
+        ui.horizontal(|ui| {
+            ui.label("Brake:");
+            ui.add(egui::Slider::new(&mut self.brake, 0.0..=1.0).show_value(false));
         });
+    }
 
-        ui.separator();
+    fn function_buttons(&mut self, connection: &mut WsConnection, ui: &mut Ui, size: Vec2) {
+        for f in (0..=28).filter_map(|f| Function::try_from(f).ok()) {
+            if ui
+                .add(
+                    Button::new(format!("F{f}"))
+                        .min_size(size)
+                        .selected(self.functions.contains(&f)),
+                )
+                .clicked()
+            {
+                self.perform(Action::Function(f), connection);
+            }
+        }
+    }
 
-        if ui.button("Release").clicked() {
-            connection.send(self.message(WiMessageType::RemoveAddress));
+    fn momentum_settings(&mut self, connection: &mut WsConnection, ui: &mut Ui) {
+        if ui
+            .checkbox(&mut self.momentum.enabled, "Drive with momentum")
+            .changed()
+            && !self.momentum.enabled
+        {
+            // Straight to the speed asked for, as there's nothing left to ramp it
+            self.brake = 0.0;
+            self.send_velocity(self.target, connection);
         }
+        egui::Grid::new(format!("{}MomentumGrid", self.slot))
+            .num_columns(2)
+            .show(ui, |ui| {
+                let rates = [
+                    ("Acceleration:", &mut self.momentum.acceleration),
+                    ("Deceleration:", &mut self.momentum.deceleration),
+                    ("Braking:", &mut self.momentum.braking),
+                ];
+                for (label, rate) in rates {
+                    ui.label(label);
+                    ui.add(
+                        DragValue::new(rate)
+                            .clamp_range(1.0..=200.0)
+                            .suffix(" steps/s"),
+                    );
+                    ui.end_row();
+                }
+            });
     }
 }
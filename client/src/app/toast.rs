@@ -0,0 +1,55 @@
+use eframe::egui;
+use eframe::egui::{Align2, Color32, Context, Frame, RichText};
+
+const TOAST_SECONDS: f64 = 5.0;
+
+struct Toast {
+    text: String,
+    color: Color32,
+    expires: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct Toasts {
+    toasts: Vec<Toast>,
+}
+
+impl Toasts {
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.push(text.into(), Color32::LIGHT_RED);
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.push(text.into(), Color32::LIGHT_BLUE);
+    }
+
+    fn push(&mut self, text: String, color: Color32) {
+        self.toasts.push(Toast {
+            text,
+            color,
+            expires: None,
+        });
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let now = ctx.input(|i| i.time);
+        self.toasts
+            .retain(|toast| toast.expires.is_none_or(|t| t > now));
+        if self.toasts.is_empty() {
+            return;
+        }
+
+        egui::Area::new("toasts")
+            .anchor(Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .show(ctx, |ui| {
+                for toast in self.toasts.iter_mut() {
+                    // Start the timer on the first frame the toast is actually shown
+                    let expires = *toast.expires.get_or_insert(now + TOAST_SECONDS);
+                    Frame::popup(ui.style()).show(ui, |ui| {
+                        ui.label(RichText::new(&toast.text).color(toast.color));
+                    });
+                    ctx.request_repaint_after(std::time::Duration::from_secs_f64(expires - now));
+                }
+            });
+    }
+}
@@ -1,5 +1,11 @@
 #[cfg(not(target_arch = "wasm32"))]
-fn main() {}
+fn main() -> eframe::Result<()> {
+    eframe::run_native(
+        "JMRI Throttle",
+        eframe::NativeOptions::default(),
+        Box::new(|cc| Box::new(app::App::new(cc))),
+    )
+}
 
 mod app;
 
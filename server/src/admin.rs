@@ -0,0 +1,134 @@
+//! An admin page at `/admin` for whoever runs the operating session, listing every client and
+//! the locos it holds, with buttons to release an address or disconnect a client. The page is
+//! static and drives the JSON endpoints under `/api/admin`, which need the `admin` permission.
+
+use crate::bridge::Bridge;
+use crate::rest::{credentials, parse_address, to_response, Loco};
+use crate::routes::with_bridge;
+use jmri_throttle_rs::message::LocoAddress;
+use jmri_throttle_rs::protocol::CommandError;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{json, Response};
+use warp::{Filter, Rejection, Reply};
+
+const ADMIN_PAGE: &str = include_str!("admin.html");
+
+#[derive(Serialize, Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub name: String,
+    pub user: String,
+    /// Unix seconds.
+    pub connected_at: u64,
+    /// Whether this is a REST API user rather than a connected throttle.
+    pub rest: bool,
+    pub locos: Vec<Loco>,
+}
+
+pub fn routes(
+    bridge: Arc<Bridge>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let page = warp::path!("admin")
+        .and(warp::get())
+        .map(|| warp::reply::html(ADMIN_PAGE));
+
+    let sessions = warp::path!("api" / "admin" / "sessions")
+        .and(warp::get())
+        .and(admin(bridge.clone()))
+        .and_then(list_sessions);
+
+    let release = warp::path!("api" / "admin" / "sessions" / Uuid / "locos" / String)
+        .and(warp::delete())
+        .and(admin(bridge.clone()))
+        .and_then(
+            |id, address: String, bridge: Arc<Bridge>, login: Result<(), CommandError>| async move {
+                let address = login.and_then(|()| parse_address(&bridge, &address));
+                let response = match address {
+                    Ok(address) => found(bridge.force_release(id, address).await),
+                    Err(e) => to_response(Err(e)),
+                };
+                Ok::<_, Infallible>(response)
+            },
+        );
+
+    let disconnect = warp::path!("api" / "admin" / "sessions" / Uuid)
+        .and(warp::delete())
+        .and(admin(bridge))
+        .and_then(
+            |id, bridge: Arc<Bridge>, login: Result<(), CommandError>| async move {
+                let response = match login {
+                    Ok(()) => found(bridge.kick(id).await),
+                    Err(e) => to_response(Err(e)),
+                };
+                Ok::<_, Infallible>(response)
+            },
+        );
+
+    page.or(sessions).or(release).or(disconnect)
+}
+
+/// Logs a request in and checks it may administer the bridge.
+fn admin(
+    bridge: Arc<Bridge>,
+) -> impl Filter<Extract = (Arc<Bridge>, Result<(), CommandError>), Error = Rejection> + Clone {
+    with_bridge(bridge)
+        .and(credentials())
+        .map(|bridge: Arc<Bridge>, credentials: Option<String>| {
+            let login = bridge.config.auth.login(credentials.as_deref());
+            let allowed = login.and_then(|login| match login.permissions.admin {
+                true => Ok(()),
+                false => Err(CommandError::AdminOnly),
+            });
+            (bridge, allowed)
+        })
+        .untuple_one()
+}
+
+fn found(found: bool) -> Response {
+    match found {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn list_sessions(
+    bridge: Arc<Bridge>,
+    login: Result<(), CommandError>,
+) -> Result<Response, Infallible> {
+    if let Err(e) = login {
+        return Ok(to_response(Err(e)));
+    }
+    let clients = bridge.clients.read().await;
+    let states = bridge.locos.read().await;
+    let mut sessions: Vec<Session> = clients
+        .values()
+        .map(|client| {
+            let mut addresses: Vec<LocoAddress> = client.addresses.keys().copied().collect();
+            addresses.sort();
+            Session {
+                id: client.id,
+                name: client.name.clone(),
+                user: client.login.user.clone(),
+                connected_at: client
+                    .connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                rest: client.sender.is_none(),
+                locos: addresses
+                    .into_iter()
+                    .map(|address| Loco {
+                        address,
+                        state: states.get(&address).cloned().unwrap_or_default(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    sessions.sort_by_key(|session| session.connected_at);
+    Ok(json(&sessions).into_response())
+}
@@ -0,0 +1,139 @@
+//! Optional logins: a shared club PIN, per-user tokens from a JSON file, or both. Without
+//! either configured every connection is let in with the default permissions, so nobody is
+//! an admin until a user with `"admin": true` is configured.
+//!
+//! The users file maps names to a token and that user's [Permissions]:
+//! `{"alice": {"token": "...", "addresses": [["Short", 1, 99]], "steal": false}}`
+
+use jmri_throttle_rs::protocol::{CommandError, Permissions};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+
+/// The user a PIN login is shown as.
+const PIN_USER: &str = "club";
+/// The user everyone is when authentication is disabled.
+const GUEST_USER: &str = "guest";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub token: String,
+    #[serde(flatten)]
+    pub permissions: Permissions,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Login {
+    pub user: String,
+    pub permissions: Permissions,
+}
+
+impl Default for Login {
+    fn default() -> Self {
+        Self {
+            user: GUEST_USER.to_string(),
+            permissions: Permissions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    /// Shared PIN that logs in with the default permissions, which don't include admin.
+    pub pin: Option<String>,
+    pub users: HashMap<String, UserConfig>,
+    /// Set once any credentials are configured, even if the users file couldn't be read,
+    /// so a broken file locks everyone out rather than letting everyone in.
+    pub required: bool,
+}
+
+impl Auth {
+    /// Reads the PIN from `BRIDGE_PIN` and the users file from the path in `BRIDGE_USERS`.
+    pub fn from_env() -> Self {
+        let pin = env::var("BRIDGE_PIN").ok().filter(|pin| !pin.is_empty());
+        let users_path = env::var_os("BRIDGE_USERS");
+        let users = users_path.as_ref().map_or_else(HashMap::new, |path| {
+            read_users(Path::new(path)).unwrap_or_else(|e| {
+                error!("Error reading users from {path:?}, no tokens will be accepted: {e}");
+                HashMap::new()
+            })
+        });
+        let required = pin.is_some() || users_path.is_some();
+        if required {
+            info!("Authentication required, {} users configured", users.len());
+        }
+        Self {
+            pin,
+            users,
+            required,
+        }
+    }
+
+    /// Checks a PIN or token, `None` when the client didn't send one.
+    pub fn login(&self, credentials: Option<&str>) -> Result<Login, CommandError> {
+        if !self.required {
+            return Ok(Login::default());
+        }
+        let credentials = credentials.ok_or(CommandError::Unauthorized)?;
+        if let Some((user, config)) = self
+            .users
+            .iter()
+            .find(|(_, config)| config.token == credentials)
+        {
+            return Ok(Login {
+                user: user.clone(),
+                permissions: config.permissions.clone(),
+            });
+        }
+        if self.pin.as_deref() == Some(credentials) {
+            return Ok(Login {
+                user: PIN_USER.to_string(),
+                permissions: Permissions::default(),
+            });
+        }
+        Err(CommandError::Unauthorized)
+    }
+}
+
+fn read_users(path: &Path) -> Result<HashMap<String, UserConfig>, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jmri_throttle_rs::message::LocoAddress;
+
+    #[test]
+    fn pin_and_tokens() {
+        let users = serde_json::from_str(
+            r#"{"alice": {"token": "t0ken", "addresses": [["Short", 1, 99]], "view_only": true}}"#,
+        )
+        .unwrap();
+        let auth = Auth {
+            pin: Some("1234".to_string()),
+            users,
+            required: true,
+        };
+
+        let alice = auth.login(Some("t0ken")).unwrap();
+        assert_eq!(alice.user, "alice");
+        assert!(alice.permissions.view_only);
+        assert!(!alice
+            .permissions
+            .may_acquire(LocoAddress::short(100).unwrap()));
+        assert!(!alice.permissions.admin);
+
+        let club = auth.login(Some("1234")).unwrap();
+        assert_eq!(club.user, PIN_USER);
+        assert!(!club.permissions.admin);
+        assert_eq!(auth.login(Some("4321")), Err(CommandError::Unauthorized));
+        assert_eq!(auth.login(None), Err(CommandError::Unauthorized));
+
+        let guest = Auth::default().login(None).unwrap();
+        assert_eq!(guest.user, GUEST_USER);
+        assert!(!guest.permissions.admin);
+    }
+}
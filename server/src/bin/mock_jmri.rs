@@ -0,0 +1,20 @@
+use log::info;
+use server::mock_jmri::MockJmri;
+use std::env;
+use std::error::Error;
+
+/// Runs the mock WiThrottle server on its own, for working on the bridge without JMRI.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::init();
+
+    let addr = env::var("MOCK_JMRI_ADDR").unwrap_or("localhost:12090".to_string());
+    let mock = MockJmri::start(&addr).await?;
+    info!("Mock JMRI listening on {}", mock.addr());
+
+    while let Some(line) = mock.next_line().await {
+        info!("Received: {line}");
+    }
+
+    Ok(())
+}
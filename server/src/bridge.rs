@@ -0,0 +1,321 @@
+use crate::auth::{Auth, Login};
+use crate::client::Client;
+use crate::jmri::coalesce::VelocityCoalescer;
+use crate::jmri::recording::{Recorder, Traffic};
+use crate::metrics::Metrics;
+use jmri_throttle_rs::layout::{RosterEntry, Turnout};
+use jmri_throttle_rs::message::{
+    Address, ClockRate, Direction, Function, LocoAddress, ThrottleId, Velocity, WiMessage,
+    WiMessageType, DEFAULT_SHORT_ADDRESS_LIMIT,
+};
+use jmri_throttle_rs::server_info::ServerInfo;
+use log::{debug, error, info};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use uuid::Uuid;
+
+/// How many lines may queue up in either direction before senders have to wait.
+const JMRI_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// JMRI's WiThrottle `host:port`, discovered over mDNS when unset.
+    pub jmri_server: Option<String>,
+    pub throttle_name: String,
+    /// Whether to advertise the bridge over mDNS.
+    pub advertise: bool,
+    /// Window in which velocity commands for one address are coalesced, zero disables it.
+    pub velocity_window: Duration,
+    /// Messages per second a single client may send, also used as the burst size.
+    pub client_rate_limit: f64,
+    /// File to record all WiThrottle traffic with JMRI to.
+    pub record_path: Option<PathBuf>,
+    /// Recording to replay instead of connecting to JMRI.
+    pub replay_path: Option<PathBuf>,
+    /// Address to accept native WiThrottle clients on, disabled when unset.
+    pub withrottle_listen: Option<String>,
+    pub auth: Auth,
+    /// PEM certificate and key to serve HTTPS and `wss://` with.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Generate a self-signed certificate, kept in the paths above when they're set.
+    pub tls_self_signed: bool,
+    /// Bare REST addresses below this are taken as short, ones from it on as long.
+    pub short_address_limit: Address,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            jmri_server: env::var("JMRI_SERVER").ok(),
+            throttle_name: env::var("JMRI_THROTTLE_NAME").unwrap_or(default.throttle_name),
+            advertise: env::var("MDNS_ADVERTISE")
+                .ok()
+                .and_then(|advertise| advertise.parse().ok())
+                .unwrap_or(default.advertise),
+            velocity_window: env::var("VELOCITY_WINDOW_MS")
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.velocity_window),
+            client_rate_limit: env::var("CLIENT_RATE_LIMIT")
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(default.client_rate_limit),
+            record_path: env::var_os("JMRI_RECORD").map(PathBuf::from),
+            replay_path: env::var_os("JMRI_REPLAY").map(PathBuf::from),
+            withrottle_listen: env::var("WITHROTTLE_LISTEN").ok(),
+            auth: Auth::from_env(),
+            tls_cert: env::var_os("TLS_CERT").map(PathBuf::from),
+            tls_key: env::var_os("TLS_KEY").map(PathBuf::from),
+            tls_self_signed: env::var("TLS_SELF_SIGNED")
+                .ok()
+                .and_then(|self_signed| self_signed.parse().ok())
+                .unwrap_or(default.tls_self_signed),
+            short_address_limit: env::var("SHORT_ADDRESS_LIMIT")
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(default.short_address_limit),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            jmri_server: None,
+            throttle_name: "TestThrottleRs".to_string(),
+            advertise: true,
+            velocity_window: Duration::from_millis(100),
+            client_rate_limit: 100.0,
+            record_path: None,
+            replay_path: None,
+            withrottle_listen: None,
+            auth: Auth::default(),
+            tls_cert: None,
+            tls_key: None,
+            tls_self_signed: false,
+            short_address_limit: DEFAULT_SHORT_ADDRESS_LIMIT,
+        }
+    }
+}
+
+pub struct JmriChannel {
+    pub tx: Sender<String>,
+    pub rx: Mutex<Receiver<String>>,
+}
+
+impl JmriChannel {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<String>(JMRI_CHANNEL_CAPACITY);
+        let rx = Mutex::new(rx);
+        JmriChannel { tx, rx }
+    }
+}
+
+/// The last state JMRI reported for an address.
+#[derive(Serialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct LocoState {
+    pub velocity: Velocity,
+    pub direction: Direction,
+    /// Functions that are on.
+    pub functions: BTreeSet<Function>,
+}
+
+impl LocoState {
+    pub fn apply(&mut self, message_type: WiMessageType) {
+        match message_type {
+            WiMessageType::Velocity(velocity) => self.velocity = velocity,
+            WiMessageType::EmergencyStop => self.velocity = Velocity::STOP,
+            WiMessageType::Direction(direction) => self.direction = direction,
+            WiMessageType::FunctionPressed(function) => {
+                self.functions.insert(function);
+            }
+            WiMessageType::FunctionReleased(function) => {
+                self.functions.remove(&function);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Everything one bridge between WebSocket clients and a JMRI connection shares. It's passed
+/// around as an `Arc<Bridge>`, so several bridges can run side by side in one process.
+pub struct Bridge {
+    pub config: Config,
+    pub clients: RwLock<HashMap<Uuid, Client>>,
+    /// The client each REST API user's commands are issued as, by user name.
+    pub rest_clients: RwLock<HashMap<String, Uuid>>,
+    pub to_jmri: JmriChannel,
+    pub from_jmri: JmriChannel,
+    /// The fast clock's time and rate, as JMRI last sent them.
+    pub time: RwLock<(i64, ClockRate)>,
+    pub jmri_connected: AtomicBool,
+    pub started: Instant,
+    /// When JMRI last sent a line and when it last answered a heartbeat.
+    pub last_jmri_line: RwLock<Option<Instant>>,
+    pub last_heartbeat: RwLock<Option<Instant>>,
+    pub locos: RwLock<HashMap<LocoAddress, LocoState>>,
+    pub roster: RwLock<Vec<RosterEntry>>,
+    pub turnouts: RwLock<Vec<Turnout>>,
+    pub server_info: RwLock<ServerInfo>,
+    pub velocity_coalescer: Arc<VelocityCoalescer>,
+    pub recorder: Option<Recorder>,
+    pub metrics: Metrics,
+}
+
+impl Bridge {
+    pub fn new(config: Config) -> Arc<Self> {
+        let to_jmri = JmriChannel::new();
+        let velocity_coalescer = Arc::new(VelocityCoalescer::new(
+            config.velocity_window,
+            to_jmri.tx.clone(),
+        ));
+        let recorder = config.record_path.as_ref().and_then(|path| {
+            Recorder::create(path)
+                .inspect(|_| info!("Recording JMRI traffic to {path:?}"))
+                .map_err(|e| error!("Error opening JMRI recording {path:?}: {e}"))
+                .ok()
+        });
+        Arc::new(Self {
+            config,
+            clients: RwLock::default(),
+            rest_clients: RwLock::default(),
+            to_jmri,
+            from_jmri: JmriChannel::new(),
+            time: RwLock::new((0, ClockRate::REAL_TIME)),
+            jmri_connected: AtomicBool::new(false),
+            started: Instant::now(),
+            last_jmri_line: RwLock::default(),
+            last_heartbeat: RwLock::default(),
+            locos: RwLock::default(),
+            roster: RwLock::default(),
+            turnouts: RwLock::default(),
+            server_info: RwLock::default(),
+            velocity_coalescer,
+            recorder,
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// Removes a disconnected client and releases every address it still held.
+    pub async fn remove_client(&self, id: Uuid) {
+        if let Some(client) = self.clients.write().await.remove(&id) {
+            let mut messages: Vec<String> = Vec::new();
+            for (address, throttle) in client.addresses {
+                let release = WiMessage::new(address, WiMessageType::RemoveAddress);
+                messages.push(release.with_throttle(throttle).to_string())
+            }
+            if let Err(e) = self.to_jmri.tx.send(messages.join("\n")).await {
+                error!("Error releasing addresses of client '{id}': {e}");
+            }
+        }
+        debug!("Removed client '{id}'");
+    }
+
+    /// Takes an address away from a client as if it had released it, returning whether the
+    /// client held it.
+    pub async fn force_release(&self, id: Uuid, address: LocoAddress) -> bool {
+        let release = {
+            let mut clients = self.clients.write().await;
+            let Some(client) = clients.get_mut(&id) else {
+                return false;
+            };
+            let Some(throttle) = client.addresses.get(&address).copied() else {
+                return false;
+            };
+            let release = WiMessage::new(address, WiMessageType::RemoveAddress);
+            let release = release.with_throttle(throttle);
+            client.update(release);
+            client.remove_address(address);
+            release
+        };
+        info!("Released address {address} of client '{id}'");
+        if let Err(e) = self.to_jmri.tx.send(release.to_string()).await {
+            error!("Error releasing address {address} of client '{id}': {e}");
+        }
+        self.close_idle_rest_client(id).await;
+        true
+    }
+
+    /// Disconnects a client, returning whether it existed. REST API users have no connection
+    /// to close, so they're removed straight away and lose their addresses.
+    pub async fn kick(&self, id: Uuid) -> bool {
+        let connected = match self.clients.read().await.get(&id) {
+            Some(client) if client.sender.is_some() => {
+                client.disconnect.notify_one();
+                true
+            }
+            Some(_) => false,
+            None => return false,
+        };
+        if !connected {
+            self.rest_clients
+                .write()
+                .await
+                .retain(|_, client| *client != id);
+            self.remove_client(id).await;
+        }
+        info!("Disconnected client '{id}'");
+        true
+    }
+
+    /// Every multi-throttle an address is held on, and the default one.
+    pub async fn throttles(&self) -> BTreeSet<ThrottleId> {
+        let clients = self.clients.read().await;
+        clients
+            .values()
+            .flat_map(|client| client.addresses.values().copied())
+            .chain([ThrottleId::DEFAULT])
+            .collect()
+    }
+
+    /// The client a REST API user's commands are issued as, while they hold any addresses.
+    pub async fn rest_client(&self, user: &str) -> Option<Uuid> {
+        self.rest_clients.read().await.get(user).copied()
+    }
+
+    /// The client for a REST API user acquiring an address, started the first time. Scripts
+    /// acquire addresses like any other throttle and keep them between requests, so it lives
+    /// until the user holds nothing.
+    pub async fn open_rest_client(&self, login: Login) -> Uuid {
+        let mut rest_clients = self.rest_clients.write().await;
+        if let Some(id) = rest_clients.get(&login.user) {
+            return *id;
+        }
+        let id = Uuid::new_v4();
+        debug!("New REST client '{id}' for {}", login.user);
+        let client = Client::detached(id, self.config.client_rate_limit).with_login(login.clone());
+        self.clients.write().await.insert(id, client);
+        rest_clients.insert(login.user, id);
+        id
+    }
+
+    /// Ends a REST API user's client once it holds no addresses.
+    pub async fn close_idle_rest_client(&self, id: Uuid) {
+        let mut rest_clients = self.rest_clients.write().await;
+        let mut clients = self.clients.write().await;
+        let idle = clients
+            .get(&id)
+            .is_some_and(|client| client.sender.is_none() && client.addresses.is_empty());
+        if idle {
+            clients.remove(&id);
+            rest_clients.retain(|_, client| *client != id);
+            debug!("Closed idle REST client '{id}'");
+        }
+    }
+
+    pub fn record(&self, traffic: Traffic, lines: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(traffic, lines);
+        }
+    }
+}
@@ -1,28 +1,220 @@
-use jmri_throttle_rs::message::Address;
-use once_cell::sync::Lazy;
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::RwLock;
+use crate::auth::Login;
+use crate::rate_limit::RateLimiter;
+use jmri_throttle_rs::message::{LocoAddress, ThrottleId, WiMessage};
+use jmri_throttle_rs::protocol::{Encoding, Payload, ServerMessage};
+use log::{error, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
 use uuid::Uuid;
 
-pub type Clients = Arc<RwLock<HashMap<Uuid, Client>>>;
+/// How many messages may wait for a client's WebSocket before it's considered too slow.
+pub const CLIENT_CHANNEL_CAPACITY: usize = 64;
 
-pub static CLIENTS: Lazy<Clients> = Lazy::new(Clients::default);
+/// A message queued for any number of clients, encoded at most once for each encoding so
+/// fanning it out doesn't serialize it again for every client.
+#[derive(Debug, Clone)]
+pub struct SharedMessage(Arc<Encoded>);
+
+#[derive(Debug)]
+struct Encoded {
+    message: ServerMessage,
+    json: OnceLock<Payload>,
+    msgpack: OnceLock<Payload>,
+}
+
+impl SharedMessage {
+    pub fn new(message: ServerMessage) -> Self {
+        Self(Arc::new(Encoded {
+            message,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+        }))
+    }
+
+    pub fn message(&self) -> &ServerMessage {
+        &self.0.message
+    }
+
+    /// The message in `encoding`, encoded by whichever client needs it first.
+    pub fn encode(&self, encoding: Encoding) -> &Payload {
+        let encoded = match encoding {
+            Encoding::Json => &self.0.json,
+            Encoding::MsgPack => &self.0.msgpack,
+        };
+        encoded.get_or_init(|| encoding.encode(&self.0.message))
+    }
+}
 
 #[derive(Debug)]
 pub struct Client {
     pub id: Uuid,
-    pub addresses: HashSet<Address>,
-    pub sender: UnboundedSender<String>,
+    pub login: Login,
+    /// Every address held, with the multi-throttle on JMRI's connection it's held on.
+    pub addresses: HashMap<LocoAddress, ThrottleId>,
+    /// The multi-throttle on JMRI's connection standing in for each of the client's own, by
+    /// the client's letter. Clients pick their letters themselves, so two clients' `A` have
+    /// to be kept apart on the one connection the bridge shares.
+    pub throttles: HashMap<ThrottleId, ThrottleId>,
+    /// Where updates are queued, `None` for clients that only issue commands, like the REST API.
+    pub sender: Option<Sender<SharedMessage>>,
+    pub rate_limiter: RateLimiter,
+    /// Shown to admins, the login's user until a native throttle sends its name.
+    pub name: String,
+    pub connected_at: SystemTime,
+    /// Notified to disconnect the client, when it has fallen too far behind or an admin
+    /// kicks it.
+    pub disconnect: Arc<Notify>,
 }
 
 impl Client {
-    pub fn new(id: Uuid, sender: UnboundedSender<String>) -> Self {
+    pub fn new(id: Uuid, sender: Sender<SharedMessage>, rate_limit: f64) -> Self {
+        Self::with_sender(id, Some(sender), rate_limit)
+    }
+
+    /// A client that holds addresses and issues commands but receives no updates.
+    pub fn detached(id: Uuid, rate_limit: f64) -> Self {
+        Self::with_sender(id, None, rate_limit)
+    }
+
+    fn with_sender(id: Uuid, sender: Option<Sender<SharedMessage>>, rate_limit: f64) -> Self {
         Self {
             id,
+            login: Login::default(),
             sender,
-            addresses: HashSet::new(),
+            addresses: HashMap::new(),
+            throttles: HashMap::new(),
+            rate_limiter: RateLimiter::new(rate_limit),
+            name: String::new(),
+            connected_at: SystemTime::now(),
+            disconnect: Arc::new(Notify::new()),
         }
     }
+
+    pub fn with_login(self, login: Login) -> Self {
+        Self {
+            name: login.user.clone(),
+            login,
+            ..self
+        }
+    }
+
+    /// Every multi-throttle on JMRI's connection the client uses.
+    pub fn jmri_throttles(&self) -> impl Iterator<Item = ThrottleId> + '_ {
+        self.addresses
+            .values()
+            .chain(self.throttles.values())
+            .copied()
+    }
+
+    /// The multi-throttle on JMRI's connection for one of the client's own, assigned from
+    /// those in no other client's use the first time. The client's own letter is kept when
+    /// it's free. `None` when every one is taken.
+    pub fn assign_throttle(
+        &mut self,
+        throttle: ThrottleId,
+        taken: &BTreeSet<ThrottleId>,
+    ) -> Option<ThrottleId> {
+        if let Some(assigned) = self.throttles.get(&throttle) {
+            return Some(*assigned);
+        }
+        let own: BTreeSet<ThrottleId> = self.jmri_throttles().collect();
+        let free = [throttle.get()]
+            .into_iter()
+            .chain(('A'..='Z').chain('a'..='z').chain('0'..='9'))
+            .filter_map(|c| ThrottleId::try_from(c).ok())
+            .find(|candidate| !taken.contains(candidate) && !own.contains(candidate))?;
+        self.throttles.insert(throttle, free);
+        Some(free)
+    }
+
+    /// Holds an address on a multi-throttle on JMRI's connection, returning the one it was
+    /// held on before.
+    pub fn add_address(
+        &mut self,
+        address: LocoAddress,
+        throttle: ThrottleId,
+    ) -> Option<ThrottleId> {
+        let held_on = self.addresses.insert(address, throttle);
+        self.forget_unused_throttles();
+        held_on
+    }
+
+    /// Stops holding an address, returning the multi-throttle on JMRI's connection it was held
+    /// on.
+    pub fn remove_address(&mut self, address: LocoAddress) -> Option<ThrottleId> {
+        let held_on = self.addresses.remove(&address);
+        self.forget_unused_throttles();
+        held_on
+    }
+
+    /// Multi-throttles left holding nothing go back for any client to use.
+    fn forget_unused_throttles(&mut self) {
+        let addresses = &self.addresses;
+        self.throttles
+            .retain(|_, assigned| addresses.values().any(|held_on| held_on == assigned));
+    }
+
+    /// Queues an update from JMRI, on the client's own letter for the multi-throttle.
+    pub fn update(&self, message: WiMessage) {
+        self.update_shared(&SharedMessage::new(ServerMessage::Update(message)));
+    }
+
+    /// Queues an update going out to several clients, which only needs encoding again for
+    /// this one if it knows the multi-throttle by another letter.
+    pub fn update_shared(&self, update: &SharedMessage) {
+        let ServerMessage::Update(message) = update.message() else {
+            return self.send_shared(update.clone());
+        };
+        let throttle = self
+            .throttles
+            .iter()
+            .find(|(_, assigned)| **assigned == message.throttle)
+            .map_or(message.throttle, |(own, _)| *own);
+        match throttle == message.throttle {
+            true => self.send_shared(update.clone()),
+            false => self.send(ServerMessage::Update(message.with_throttle(throttle))),
+        }
+    }
+
+    pub fn send(&self, message: ServerMessage) {
+        self.send_shared(SharedMessage::new(message));
+    }
+
+    /// Queues a message without waiting, so a slow client can never hold up the JMRI reader.
+    /// A client whose queue is full is disconnected rather than left with stale state.
+    pub fn send_shared(&self, message: SharedMessage) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("Client '{}' is not keeping up, disconnecting", self.id);
+                self.disconnect.notify_one();
+            }
+            Err(e) => error!("Error queueing message for client '{}': {e}", self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_messages_are_encoded_once() {
+        let message = SharedMessage::new(ServerMessage::Ack { request_id: 1 });
+        let copy = message.clone();
+        let json = message.encode(Encoding::Json);
+        assert!(std::ptr::eq(json, copy.encode(Encoding::Json)));
+        assert_eq!(
+            json,
+            &Payload::Text(r#"{"Ack":{"request_id":1}}"#.to_string())
+        );
+        assert!(matches!(copy.encode(Encoding::MsgPack), Payload::Binary(_)));
+    }
 }
@@ -0,0 +1,122 @@
+//! mDNS/Zeroconf: finding JMRI's WiThrottle server when none is configured, and announcing
+//! the bridge so clients on the network can find it without typing in a URL.
+
+use jmri_throttle_rs::protocol::BRIDGE_SERVICE;
+use log::{debug, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// What JMRI advertises its WiThrottle server as.
+pub const WITHROTTLE_SERVICE: &str = "_withrottle._tcp.local.";
+
+/// Browses for a WiThrottle server, returning the `host:port` of the first one resolved.
+pub async fn discover_jmri(
+    daemon: &ServiceDaemon,
+    wait: Duration,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let events = daemon.browse(WITHROTTLE_SERVICE)?;
+    let found = timeout(wait, async {
+        while let Ok(event) = events.recv_async().await {
+            debug!("mDNS event: {event:?}");
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if let Some(addr) = service_addr(&info) {
+                    info!("Discovered JMRI '{}' at {addr}", info.get_fullname());
+                    return Some(addr);
+                }
+            }
+        }
+        None
+    })
+    .await;
+    daemon.stop_browse(WITHROTTLE_SERVICE)?;
+
+    found
+        .ok()
+        .flatten()
+        .ok_or_else(|| format!("No {WITHROTTLE_SERVICE} service found within {wait:?}").into())
+}
+
+/// Picks a reachable `host:port` for a resolved service, preferring IPv4.
+pub fn service_addr(info: &ServiceInfo) -> Option<String> {
+    let addresses = info.get_addresses();
+    let ip = addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.iter().next())?;
+    Some(match ip {
+        IpAddr::V4(ip) => format!("{ip}:{}", info.get_port()),
+        IpAddr::V6(ip) => format!("[{ip}]:{}", info.get_port()),
+    })
+}
+
+/// The mDNS host name the bridge is announced under.
+pub fn host_name(instance_name: &str) -> String {
+    format!("{}.local.", instance_name.replace(' ', "-"))
+}
+
+/// Announces the bridge's WebSocket endpoint on every interface until the daemon shuts down.
+/// The `scheme` property tells clients whether to connect with `ws` or `wss`.
+pub fn advertise(
+    daemon: &ServiceDaemon,
+    instance_name: &str,
+    port: u16,
+    tls: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let host_name = host_name(instance_name);
+    let scheme = if tls { "wss" } else { "ws" };
+    let properties = [("path", "/ws"), ("scheme", scheme)];
+    let info = ServiceInfo::new(
+        BRIDGE_SERVICE,
+        instance_name,
+        &host_name,
+        "",
+        port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    daemon.register(info)?;
+    info!("Advertising '{instance_name}' as {BRIDGE_SERVICE} on port {port}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_addr_prefers_ipv4() {
+        let ips: &[IpAddr] = &["fe80::1".parse().unwrap(), "192.168.1.20".parse().unwrap()];
+        let info =
+            ServiceInfo::new(WITHROTTLE_SERVICE, "JMRI", "jmri.local.", ips, 12090, None).unwrap();
+        assert_eq!(service_addr(&info).as_deref(), Some("192.168.1.20:12090"));
+    }
+
+    /// Resolves a WiThrottle service announced by a second daemon on this machine.
+    #[tokio::test]
+    async fn discovers_loopback_responder() {
+        let responder = ServiceDaemon::new().unwrap();
+        let info = ServiceInfo::new(
+            WITHROTTLE_SERVICE,
+            "Loopback JMRI",
+            "loopback-jmri.local.",
+            "",
+            12090,
+            None,
+        )
+        .unwrap()
+        .enable_addr_auto();
+        responder.register(info).unwrap();
+
+        let daemon = ServiceDaemon::new().unwrap();
+        let addr = discover_jmri(&daemon, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(addr.ends_with(":12090"));
+
+        daemon.shutdown().unwrap();
+        responder.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+//! Liveness and readiness for supervisors. Liveness only says the HTTP server is answering,
+//! readiness reports on the JMRI connection and fails with 503 while JMRI is unreachable.
+
+use crate::bridge::Bridge;
+use crate::jmri::HEARTBEAT_INTERVAL;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeartbeatStatus {
+    /// JMRI answered a heartbeat within the last two intervals.
+    Ok,
+    Late,
+    /// JMRI hasn't answered a heartbeat yet, e.g. while replaying a recording.
+    None,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub jmri_connected: bool,
+    pub ms_since_last_jmri_line: Option<u128>,
+    pub heartbeat: HeartbeatStatus,
+    pub heartbeat_misses: u64,
+    pub clients: usize,
+    pub uptime_seconds: u64,
+}
+
+impl Readiness {
+    pub async fn of(bridge: &Bridge) -> Self {
+        let now = Instant::now();
+        let jmri_connected = bridge.jmri_connected.load(Ordering::Relaxed);
+        let heartbeat = match *bridge.last_heartbeat.read().await {
+            Some(last) if now - last <= HEARTBEAT_INTERVAL * 2 => HeartbeatStatus::Ok,
+            Some(_) => HeartbeatStatus::Late,
+            None => HeartbeatStatus::None,
+        };
+        let clients = bridge.clients.read().await;
+        Self {
+            ready: jmri_connected,
+            jmri_connected,
+            ms_since_last_jmri_line: bridge
+                .last_jmri_line
+                .read()
+                .await
+                .map(|last| (now - last).as_millis()),
+            heartbeat,
+            heartbeat_misses: bridge.metrics.heartbeat_misses.get(),
+            // REST API users have no connection to count
+            clients: clients.values().filter(|c| c.sender.is_some()).count(),
+            uptime_seconds: (now - bridge.started).as_secs(),
+        }
+    }
+}
@@ -1,61 +1,143 @@
+pub mod coalesce;
 mod handle_message;
-pub use handle_message::handle_message;
+pub mod recording;
+pub use handle_message::{handle_command, handle_message, process_message};
 
-use crate::client::CLIENTS;
-use crate::{FROM_JMRI, TIME, TO_JMRI};
+use crate::bridge::Bridge;
+use crate::client::SharedMessage;
+use crate::discovery::discover_jmri;
+use crate::jmri::recording::Traffic;
 
-use futures::future::join4;
 use futures::{SinkExt, StreamExt};
-use jmri_throttle_rs::message::{WiMessage, WiMessageType};
-use log::{debug, error, info};
+use jmri_throttle_rs::layout::{parse_roster, parse_turnout_update, parse_turnouts};
+use jmri_throttle_rs::message::{LocoAddress, Target, ThrottleId, WiMessage, WiMessageType};
+use jmri_throttle_rs::protocol::ServerMessage;
+use jmri_throttle_rs::server_info::parse_server_info;
+use log::{debug, error, info, warn};
+use mdns_sd::ServiceDaemon;
+use once_cell::sync::Lazy;
 use regex::Regex;
-use std::env;
-use std::error::Error;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
-use tokio::sync::Notify;
-use tokio::time::sleep;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio::time::{interval, sleep};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 use uuid::Uuid;
 
 const NEWLINE: char = '\n';
+const DEFAULT_JMRI_SERVER: &str = "localhost:12090";
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
+/// How long to wait before reconnecting to JMRI, doubled after every failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
-pub async fn jmri_conn(notify: Arc<Notify>) -> Result<(), Box<dyn Error>> {
+/// Connects to JMRI, reconnecting whenever the connection is lost or can't be made with a
+/// delay that doubles after every failed attempt.
+pub async fn jmri_conn(bridge: Arc<Bridge>) {
     let my_id = Uuid::new_v4();
     debug!("Server's ID: {my_id}");
 
-    let jmri_server = &env::var("JMRI_SERVER").unwrap_or("localhost:12090".to_string());
-    let throttle_name = &env::var("JMRI_THROTTLE_NAME").unwrap_or("TestThrottleRs".to_string());
+    let jmri_server = &match &bridge.config.jmri_server {
+        Some(jmri_server) => jmri_server.clone(),
+        None => discover_jmri_server().await,
+    };
 
-    let jmri_conn = TcpStream::connect(jmri_server).await.unwrap_or_else(|e| {
-        error!("Error connecting to JMRI at '{jmri_server}': {e}");
-        std::process::exit(1);
-    });
-    let (mut jmri_tx, mut jmri_rx) = Framed::new(jmri_conn, LinesCodec::new()).split::<String>();
-
-    // Notify we're connected and main init can continue
-    info!("Successfully connected to JMRI at: {jmri_server}");
-    notify.notify_one();
+    // Outlives the connections, so lines are handled in order across reconnects
+    {
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            let mut from_jmri = bridge.from_jmri.rx.lock().await;
+            while let Some(line) = from_jmri.recv().await {
+                handle_jmri_line(&bridge, &line).await;
+            }
+        });
+    }
 
-    // TODO: figure out if this is even working...
-    let heartbeat_handle = tokio::spawn(async move {
-        if let Err(e) = TO_JMRI.tx.read().await.send("*".into()) {
-            error!("Error sending heartbeat to JMRI: {e}");
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        bridge.metrics.jmri_connects.inc();
+        match TcpStream::connect(jmri_server).await {
+            Ok(stream) => {
+                info!("Successfully connected to JMRI at: {jmri_server}");
+                delay = RECONNECT_DELAY;
+                if let Err(e) = run_connection(&bridge, stream, my_id).await {
+                    error!("Error on connection to JMRI: {e}");
+                }
+                bridge.jmri_connected.store(false, Ordering::Relaxed);
+                warn!("Lost connection to JMRI at '{jmri_server}'");
+            }
+            Err(e) => error!("Error connecting to JMRI at '{jmri_server}': {e}"),
         }
-        sleep(Duration::from_secs(3)).await;
-    });
+        info!("Reconnecting to JMRI in {delay:?}");
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
 
-    let read_handle = tokio::spawn(async move {
-        while let Some(line) = jmri_rx.next().await {
-            let line = match line {
-                Ok(line) => line,
+/// Runs one connection to JMRI until either direction fails or JMRI closes it.
+async fn run_connection(
+    bridge: &Arc<Bridge>,
+    stream: TcpStream,
+    my_id: Uuid,
+) -> Result<(), LinesCodecError> {
+    let (mut jmri_tx, mut jmri_rx) = Framed::new(stream, LinesCodec::new()).split::<String>();
+
+    // Initial setup message to JMRI, then everything clients held when it was last lost, as
+    // JMRI has forgotten about it
+    let throttle_name = &bridge.config.throttle_name;
+    let mut setup = vec![format!("HU{my_id}"), format!("N{throttle_name}")];
+    let mut held: Vec<(LocoAddress, ThrottleId)> = bridge
+        .clients
+        .read()
+        .await
+        .values()
+        .flat_map(|client| client.addresses.iter().map(|(a, t)| (*a, *t)))
+        .collect();
+    held.sort();
+    setup.extend(held.into_iter().map(|(address, throttle)| {
+        WiMessage::new(address, WiMessageType::AddAddress)
+            .with_throttle(throttle)
+            .to_string()
+    }));
+    setup.extend(take_queued_addresses(bridge).await);
+    let setup = setup.join(&NEWLINE.to_string());
+    bridge.record(Traffic::ToJmri, &setup);
+    count_lines_to_jmri(bridge, &setup);
+    jmri_tx.send(setup).await?;
+
+    bridge.jmri_connected.store(true, Ordering::Relaxed);
+
+    // JMRI answers the throttle's name with its heartbeat interval, e.g. `*10`, so sending it
+    // again makes a round trip. A heartbeat is missed when the last one had no answer in time.
+    let heartbeat = async {
+        let mut interval = interval(HEARTBEAT_INTERVAL);
+        let mut sent: Option<Instant> = None;
+        loop {
+            interval.tick().await;
+            let answered = *bridge.last_heartbeat.read().await;
+            if sent.is_some_and(|sent| answered.is_none_or(|answered| answered < sent)) {
+                warn!("JMRI didn't answer the last heartbeat");
+                bridge.metrics.heartbeat_misses.inc();
+            }
+            // A heartbeat stuck behind a full queue would arrive too late anyway
+            match bridge.to_jmri.tx.try_send(format!("N{throttle_name}")) {
+                Ok(()) => sent = Some(Instant::now()),
                 Err(e) => {
-                    error!("Error reading from JMRI: {e}");
-                    break;
+                    warn!("Missed heartbeat to JMRI: {e}");
+                    bridge.metrics.heartbeat_misses.inc();
+                    sent = None;
                 }
-            };
+            }
+        }
+    };
+
+    let read = async {
+        while let Some(line) = jmri_rx.next().await {
+            let line = line?;
             let line = line.trim();
 
             // Skip empty lines
@@ -63,62 +145,191 @@ pub async fn jmri_conn(notify: Arc<Notify>) -> Result<(), Box<dyn Error>> {
                 continue;
             }
 
-            // debug!("Message from JMRI (len={}): {line}", line.len());
-            if let Err(e) = FROM_JMRI.tx.read().await.send(line.into()) {
+            bridge.record(Traffic::FromJmri, line);
+            bridge
+                .metrics
+                .jmri_lines
+                .with_label_values(&["from_jmri"])
+                .inc();
+            *bridge.last_jmri_line.write().await = Some(Instant::now());
+            if line.starts_with('*') {
+                *bridge.last_heartbeat.write().await = Some(Instant::now());
+                continue;
+            }
+            if let Err(e) = bridge.from_jmri.tx.send(line.into()).await {
                 error!("Error sending message from JMRI: {e}");
             }
         }
-    });
+        Ok(())
+    };
 
-    // TODO: Is there a better place for this?
-    let client_handle = tokio::spawn(async move {
-        let reg = Regex::new("^(PTA|PTL|RCD|PTT|PRT|PRL|RL)").unwrap();
-        while let Some(line) = FROM_JMRI.rx.write().await.next().await {
-            if reg.is_match(&line) {
+    let write = async {
+        let mut to_jmri = bridge.to_jmri.rx.lock().await;
+        while let Some(line) = to_jmri.recv().await {
+            if line.is_empty() {
                 continue;
             }
-            match WiMessage::from_str(&line) {
-                Ok(message) => {
-                    let clients = CLIENTS.read().await;
-                    if let WiMessageType::Time(t) = message.message_type {
-                        *TIME.write().await = t;
-                        clients.values().for_each(|client| {
-                            let message = serde_json::to_string(&message).unwrap();
-                            client.sender.send(message).unwrap();
-                        });
-                    } else {
-                        clients
-                            .iter()
-                            .filter(|(_uuid, client)| client.addresses.contains(&message.address))
-                            .for_each(|(_uuid, client)| {
-                                let message = serde_json::to_string(&message).unwrap();
-                                info!("Sending message to client: {message}");
-                                client.sender.send(message).unwrap();
-                            });
-                    }
-                }
-                Err(e) => error!("Error parsing message: {e}"),
-            }
+            debug!("Sending message to JMRI: {line}");
+            bridge.record(Traffic::ToJmri, &line);
+            count_lines_to_jmri(bridge, &line);
+            jmri_tx.send(line).await?;
         }
-    });
+        Ok(())
+    };
 
-    // Initial setup message to JMRI
-    jmri_tx
-        .send(format!("HU{my_id}{NEWLINE}N{throttle_name}"))
-        .await
-        .unwrap();
+    tokio::select! {
+        result = read => result,
+        result = write => result,
+        _ = heartbeat => Ok(()),
+    }
+}
 
-    let write_handle = tokio::spawn(async move {
-        while let Some(line) = TO_JMRI.rx.write().await.next().await {
-            if line.is_empty() {
-                continue;
+/// Empties what was queued for JMRI while it was away. Speeds, functions and the like are
+/// dropped as they're stale by now and could move a loco unexpectedly, but acquiring and
+/// releasing addresses still has to happen.
+async fn take_queued_addresses(bridge: &Bridge) -> Vec<String> {
+    let mut to_jmri = bridge.to_jmri.rx.lock().await;
+    let mut kept = Vec::new();
+    let mut dropped = 0;
+    while let Ok(lines) = to_jmri.try_recv() {
+        for line in lines.lines() {
+            match WiMessage::from_str(line) {
+                Ok(message) if message.message_type.is_address() => kept.push(line.to_string()),
+                _ => dropped += 1,
             }
-            debug!("Sending message to JMRI: {line}");
-            jmri_tx.send(line).await.unwrap();
         }
-    });
+    }
+    if dropped > 0 {
+        warn!("Dropped {dropped} lines queued while JMRI was disconnected");
+    }
+    kept
+}
+
+/// Counts what's sent to JMRI by line, as several can be sent at once.
+fn count_lines_to_jmri(bridge: &Bridge, lines: &str) {
+    bridge
+        .metrics
+        .jmri_lines
+        .with_label_values(&["to_jmri"])
+        .inc_by(lines.lines().count() as u64);
+}
+
+/// Looks for JMRI over mDNS, falling back to JMRI's default port on this machine.
+async fn discover_jmri_server() -> String {
+    let discovered = match ServiceDaemon::new() {
+        Ok(daemon) => {
+            let discovered = discover_jmri(&daemon, DISCOVERY_TIMEOUT).await;
+            let _ = daemon.shutdown();
+            discovered
+        }
+        Err(e) => Err(e.into()),
+    };
+    discovered.unwrap_or_else(|e| {
+        warn!("Couldn't discover JMRI, trying {DEFAULT_JMRI_SERVER}: {e}");
+        DEFAULT_JMRI_SERVER.to_string()
+    })
+}
+
+/// Lines JMRI sends in normal operation that the bridge has no use for: consists, turnout and
+/// route titles, track power, and the function labels sent on acquiring an address.
+static IGNORED_LINES: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(RCD|PTT|PRT|PRL|PPA|M.L|M.A[^<]*<;>\]\\\[)").unwrap());
 
-    let _ = join4(read_handle, write_handle, heartbeat_handle, client_handle).await;
+/// Parses a line from JMRI and fans it out to the clients it concerns.
+pub async fn handle_jmri_line(bridge: &Bridge, line: &str) {
+    if let Some(info_line) = parse_server_info(line) {
+        let mut server_info = bridge.server_info.write().await;
+        let message = match server_info.apply(info_line) {
+            Some(notification) => ServerMessage::Notification(notification),
+            None => ServerMessage::ServerInfo(server_info.clone()),
+        };
+        drop(server_info);
+        let message = SharedMessage::new(message);
+        let clients = bridge.clients.read().await;
+        clients
+            .values()
+            .for_each(|client| client.send_shared(message.clone()));
+        return;
+    }
+    if let Some(roster) = parse_roster(line) {
+        *bridge.roster.write().await = roster;
+        return;
+    }
+    if let Some(turnouts) = parse_turnouts(line) {
+        *bridge.turnouts.write().await = turnouts;
+        return;
+    }
+    if let Some((system_name, state)) = parse_turnout_update(line) {
+        let mut turnouts = bridge.turnouts.write().await;
+        if let Some(turnout) = turnouts.iter_mut().find(|t| t.system_name == system_name) {
+            turnout.state = state;
+        }
+        return;
+    }
+    if IGNORED_LINES.is_match(line) {
+        return;
+    }
+    match WiMessage::from_str(line) {
+        Ok(message) => {
+            // `*` is about every address held on that multi-throttle
+            let on_throttle = |addresses: &HashMap<LocoAddress, ThrottleId>| {
+                addresses
+                    .iter()
+                    .filter(|(_, throttle)| **throttle == message.throttle)
+                    .map(|(address, _)| *address)
+                    .collect::<Vec<_>>()
+            };
+            let addresses = match message.address {
+                Target::Loco(address) => vec![address],
+                Target::All => bridge
+                    .clients
+                    .read()
+                    .await
+                    .values()
+                    .flat_map(|client| on_throttle(&client.addresses))
+                    .collect(),
+            };
+            let mut locos = bridge.locos.write().await;
+            match message.message_type {
+                WiMessageType::Time(..) => {}
+                WiMessageType::RemoveAddress => addresses.iter().for_each(|address| {
+                    locos.remove(address);
+                }),
+                message_type => addresses
+                    .iter()
+                    .for_each(|address| locos.entry(*address).or_default().apply(message_type)),
+            }
+            drop(locos);
 
-    Ok(())
+            let clients = bridge.clients.read().await;
+            let update = SharedMessage::new(ServerMessage::Update(message));
+            if let WiMessageType::Time(time, rate) = message.message_type {
+                *bridge.time.write().await = (time, rate);
+                clients
+                    .values()
+                    .for_each(|client| client.send_shared(update.clone()));
+            } else {
+                clients
+                    .iter()
+                    .filter(|(_uuid, client)| match message.address {
+                        Target::Loco(address) => {
+                            client.addresses.get(&address) == Some(&message.throttle)
+                        }
+                        Target::All => !on_throttle(&client.addresses).is_empty(),
+                    })
+                    .for_each(|(_uuid, client)| {
+                        info!("Sending message to client: {message:?}");
+                        client.update_shared(&update);
+                    });
+            }
+        }
+        Err(e) => {
+            error!("Error parsing message: {e}");
+            bridge
+                .metrics
+                .parse_errors
+                .with_label_values(&["jmri"])
+                .inc();
+        }
+    }
 }
@@ -0,0 +1,298 @@
+use jmri_throttle_rs::message::{
+    LocoAddress, Target, ThrottleId, Velocity, WiMessage, WiMessageType,
+};
+use jmri_throttle_rs::protocol::CommandError;
+use log::{debug, error};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{Permit, Sender};
+use tokio::time::sleep;
+
+/// Forwards at most one velocity per address per window to JMRI. The first velocity is sent
+/// straight away and opens a window, anything arriving while it's open replaces the pending
+/// value, which is sent when the window closes so the last speed always reaches JMRI.
+///
+/// Room in the JMRI queue is reserved before deciding what to send, so the lock on what's
+/// pending is never held while JMRI is slow, and lines still go out in the order decided.
+pub struct VelocityCoalescer {
+    window: Duration,
+    to_jmri: Sender<String>,
+    pending: Mutex<Pending>,
+}
+
+/// An entry exists while a window is open, holding the window's number and the latest
+/// velocity not yet sent. The number tells a flush whether its window was closed early and
+/// another opened since.
+#[derive(Default)]
+struct Pending {
+    windows: HashMap<LocoAddress, (u64, Option<WiMessage>)>,
+    opened: u64,
+}
+
+impl VelocityCoalescer {
+    pub fn new(window: Duration, to_jmri: Sender<String>) -> Self {
+        Self {
+            window,
+            to_jmri,
+            pending: Mutex::default(),
+        }
+    }
+
+    pub async fn submit(self: &Arc<Self>, message: WiMessage) -> Result<(), CommandError> {
+        // A speed held back in an open window needs no room in the queue
+        let message = match self.hold(message) {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let permit = self.reserve().await?;
+        let Target::Loco(address) = message.address else {
+            return send(permit, [message]);
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let velocity = match message.message_type {
+            WiMessageType::Velocity(velocity) => velocity,
+            WiMessageType::EmergencyStop => Velocity::STOP,
+            // A pending speed is for the multi-throttle the address was held on, so it's
+            // dropped when the address is released or moves to another one
+            message_type if message_type.is_address() => {
+                pending.windows.remove(&address);
+                return send(permit, [message]);
+            }
+            // Anything else goes out after a pending speed, so a direction change can't
+            // overtake the speed that came before it. The window stays open with nothing
+            // left to send.
+            _ => {
+                let held = pending
+                    .windows
+                    .get_mut(&address)
+                    .and_then(|(_, slot)| slot.take());
+                return send(permit, held.into_iter().chain([message]));
+            }
+        };
+
+        // Stops are never held back, and make any pending speed stale
+        if self.window.is_zero() || velocity == Velocity::STOP {
+            pending.windows.remove(&address);
+            return send(permit, [message]);
+        }
+        if let Some((_, slot)) = pending.windows.get_mut(&address) {
+            *slot = Some(message);
+            return Ok(());
+        }
+
+        pending.opened += 1;
+        let window = pending.opened;
+        pending.windows.insert(address, (window, None));
+        tokio::spawn(self.clone().flush(address, window));
+        send(permit, [message])
+    }
+
+    /// Replaces the pending speed if the message is a speed for an address with an open window,
+    /// otherwise hands the message back.
+    fn hold(&self, message: WiMessage) -> Option<WiMessage> {
+        let (Target::Loco(address), WiMessageType::Velocity(velocity)) =
+            (&message.address, &message.message_type)
+        else {
+            return Some(message);
+        };
+        if *velocity == Velocity::STOP {
+            return Some(message);
+        }
+        match self.pending.lock().unwrap().windows.get_mut(address) {
+            Some((_, slot)) => {
+                *slot = Some(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    /// Stops every locomotive on the given multi-throttles, dropping any speed still held back.
+    pub async fn emergency_stop(
+        &self,
+        throttles: impl IntoIterator<Item = ThrottleId>,
+    ) -> Result<(), CommandError> {
+        let permit = self.reserve().await?;
+        self.pending.lock().unwrap().windows.clear();
+        debug!("Forwarding emergency stop to JMRI");
+        let stops = throttles.into_iter().map(|throttle| {
+            WiMessage::new(Target::All, WiMessageType::EmergencyStop).with_throttle(throttle)
+        });
+        send(permit, stops)
+    }
+
+    async fn flush(self: Arc<Self>, address: LocoAddress, window: u64) {
+        loop {
+            sleep(self.window).await;
+            let permit = match self.reserve().await {
+                Ok(permit) => permit,
+                Err(e) => {
+                    error!("Error forwarding velocity for {address}: {e}");
+                    return;
+                }
+            };
+            let mut pending = self.pending.lock().unwrap();
+            match pending.windows.get_mut(&address) {
+                Some((open, slot @ Some(_))) if *open == window => {
+                    // Can't fail, the permit was reserved already
+                    let _ = send(permit, slot.take());
+                }
+                Some((open, None)) if *open == window => {
+                    pending.windows.remove(&address);
+                    return;
+                }
+                // Closed early by a stop or release, and maybe another window opened since
+                _ => return,
+            }
+        }
+    }
+
+    /// Room for one more item in the JMRI queue.
+    async fn reserve(&self) -> Result<Permit<'_, String>, CommandError> {
+        self.to_jmri
+            .reserve()
+            .await
+            .map_err(|_| CommandError::JmriDisconnected)
+    }
+}
+
+/// Sends messages to JMRI as one item of the queue, keeping them together.
+fn send(
+    permit: Permit<'_, String>,
+    messages: impl IntoIterator<Item = WiMessage>,
+) -> Result<(), CommandError> {
+    let lines: Vec<String> = messages.into_iter().map(|m| m.to_string()).collect();
+    debug!("Forwarding to JMRI: {lines:?}");
+    permit.send(lines.join("\n"));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jmri_throttle_rs::message::Direction;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn coalescer() -> (Arc<VelocityCoalescer>, Receiver<String>) {
+        let (tx, rx) = mpsc::channel(16);
+        (Arc::new(VelocityCoalescer::new(WINDOW, tx)), rx)
+    }
+
+    fn velocity(speed: i16) -> WiMessage {
+        let velocity = Velocity::try_from(speed).unwrap();
+        WiMessage::new(
+            LocoAddress::short(3).unwrap(),
+            WiMessageType::Velocity(velocity),
+        )
+    }
+
+    /// Everything sent once every window has had time to close.
+    async fn sent(rx: &mut Receiver<String>) -> Vec<String> {
+        sleep(WINDOW * 3).await;
+        let mut lines = Vec::new();
+        while let Ok(line) = rx.try_recv() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_last_velocity_of_a_window_is_sent() {
+        let (coalescer, mut rx) = coalescer();
+        for speed in [10, 20, 30] {
+            coalescer.submit(velocity(speed)).await.unwrap();
+        }
+        assert_eq!(sent(&mut rx).await, ["MTAS3<;>V10", "MTAS3<;>V30"]);
+
+        // A new window opens once the last one has closed
+        coalescer.submit(velocity(40)).await.unwrap();
+        assert_eq!(sent(&mut rx).await, ["MTAS3<;>V40"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_go_out_straight_away() {
+        let (coalescer, mut rx) = coalescer();
+        coalescer.submit(velocity(10)).await.unwrap();
+        coalescer.submit(velocity(20)).await.unwrap();
+        coalescer.submit(velocity(0)).await.unwrap();
+        coalescer.submit(velocity(5)).await.unwrap();
+        assert_eq!(
+            sent(&mut rx).await,
+            ["MTAS3<;>V10", "MTAS3<;>V0", "MTAS3<;>V5"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn direction_changes_follow_the_pending_velocity() {
+        let (coalescer, mut rx) = coalescer();
+        let s3 = LocoAddress::short(3).unwrap();
+        coalescer.submit(velocity(10)).await.unwrap();
+        coalescer.submit(velocity(20)).await.unwrap();
+        let reverse = WiMessage::new(s3, WiMessageType::Direction(Direction::Reverse));
+        coalescer.submit(reverse).await.unwrap();
+        assert_eq!(
+            sent(&mut rx).await,
+            ["MTAS3<;>V10", "MTAS3<;>V20\nMTAS3<;>R0"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_queue_holds_up_no_other_address() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let coalescer = Arc::new(VelocityCoalescer::new(WINDOW, tx));
+        let l5 = |speed| {
+            let velocity = Velocity::try_from(speed).unwrap();
+            WiMessage::new(
+                LocoAddress::long(5).unwrap(),
+                WiMessageType::Velocity(velocity),
+            )
+        };
+        coalescer.submit(velocity(10)).await.unwrap();
+        // The queue is full, so this waits for room
+        let blocked = tokio::spawn({
+            let coalescer = coalescer.clone();
+            async move { coalescer.submit(l5(30)).await }
+        });
+        sleep(WINDOW / 2).await;
+        // but speeds for other addresses are still coalesced meanwhile
+        coalescer.submit(velocity(20)).await.unwrap();
+        assert!(!blocked.is_finished());
+        assert_eq!(rx.recv().await.unwrap(), "MTAS3<;>V10");
+        blocked.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "MTAL5<;>V30");
+        assert_eq!(rx.recv().await.unwrap(), "MTAS3<;>V20");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_drop_the_pending_velocity() {
+        let (coalescer, mut rx) = coalescer();
+        let s3 = LocoAddress::short(3).unwrap();
+        coalescer.submit(velocity(10)).await.unwrap();
+        coalescer.submit(velocity(20)).await.unwrap();
+        let release = WiMessage::new(s3, WiMessageType::RemoveAddress);
+        coalescer.submit(release).await.unwrap();
+        assert_eq!(sent(&mut rx).await, ["MTAS3<;>V10", "MT-S3<;>S3"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moving_throttles_drops_the_pending_velocity() {
+        let (coalescer, mut rx) = coalescer();
+        let s3 = LocoAddress::short(3).unwrap();
+        coalescer.submit(velocity(10)).await.unwrap();
+        coalescer.submit(velocity(20)).await.unwrap();
+        let b = ThrottleId::try_from('B').unwrap();
+        let acquire = WiMessage::new(s3, WiMessageType::AddAddress).with_throttle(b);
+        coalescer.submit(acquire).await.unwrap();
+        coalescer
+            .submit(velocity(30).with_throttle(b))
+            .await
+            .unwrap();
+        assert_eq!(
+            sent(&mut rx).await,
+            ["MTAS3<;>V10", "MB+S3<;>S3", "MBAS3<;>V30"]
+        );
+    }
+}
@@ -1,41 +1,475 @@
-use crate::client::CLIENTS;
-use crate::TO_JMRI;
+use crate::bridge::Bridge;
 use jmri_throttle_rs::message::WiMessageType::RemoveAddress;
-use jmri_throttle_rs::message::{WiMessage, WiMessageType};
-use log::{debug, error};
+use jmri_throttle_rs::message::{LocoAddress, Target, ThrottleId, WiMessage, WiMessageType};
+use jmri_throttle_rs::protocol::{ClientMessage, CommandError, Encoding, RequestId, ServerMessage};
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use uuid::Uuid;
 use warp::ws::Message;
 
-pub async fn handle_message(id: Uuid, message: Message) {
-    if !message.is_text() {
-        debug!("Text not received to '{id}': {message:?}");
+pub async fn handle_message(bridge: &Bridge, id: Uuid, encoding: Encoding, message: Message) {
+    if !message.is_text() && !message.is_binary() {
+        debug!("Data not received to '{id}': {message:?}");
         return;
     }
-    let message = message.to_str().unwrap();
-    let message = match serde_json::from_str::<WiMessage>(message) {
+    let message = message.as_bytes();
+    let ClientMessage {
+        request_id,
+        message,
+    } = match encoding.decode::<ClientMessage>(message) {
         Ok(message) => message,
         Err(e) => {
             error!("Deserialize error(uid={id}, e={e})");
+            bridge
+                .metrics
+                .parse_errors
+                .with_label_values(&["client"])
+                .inc();
+            let request_id = salvage_request_id(encoding, message);
+            reply(bridge, id, request_id, Err(CommandError::Parse(e))).await;
             return;
         }
     };
-    debug!("Received message(uid={id}, message={message:?})");
+    debug!("Received message(uid={id}, request_id={request_id:?}, message={message:?})");
+    handle_command(bridge, id, request_id, message).await;
+}
+
+/// Runs a decoded command from any kind of client and replies with the outcome.
+pub async fn handle_command(
+    bridge: &Bridge,
+    id: Uuid,
+    request_id: Option<RequestId>,
+    message: WiMessage,
+) {
+    let result = process_message(bridge, id, message).await;
+    let outcome = match &result {
+        Ok(()) => "accepted",
+        Err(e) => {
+            debug!("Rejected message(uid={id}, message={message:?}, e={e})");
+            "rejected"
+        }
+    };
+    bridge
+        .metrics
+        .client_commands
+        .with_label_values(&[outcome])
+        .inc();
+    reply(bridge, id, request_id, result).await;
+}
+
+/// Checks a command against the client's permissions and forwards it to JMRI. Commands for
+/// all addresses are sent for each address the client holds on that multi-throttle of its own,
+/// as `*` would reach other clients' locos too.
+pub async fn process_message(
+    bridge: &Bridge,
+    id: Uuid,
+    message: WiMessage,
+) -> Result<(), CommandError> {
+    let address = match message.address {
+        Target::Loco(address) => address,
+        Target::All if message.message_type == WiMessageType::AddAddress => {
+            return Err(CommandError::Parse("Can't acquire every address".into()));
+        }
+        Target::All => {
+            let mut addresses: Vec<LocoAddress> = match bridge.clients.read().await.get(&id) {
+                Some(client) => {
+                    let assigned = client.throttles.get(&message.throttle);
+                    client
+                        .addresses
+                        .iter()
+                        .filter(|(_, throttle)| Some(*throttle) == assigned)
+                        .map(|(address, _)| *address)
+                        .collect()
+                }
+                None => return Ok(()),
+            };
+            addresses.sort();
+            for address in addresses {
+                let WiMessage {
+                    message_type,
+                    throttle,
+                    ..
+                } = message;
+                process_address(bridge, id, address, message_type, throttle).await?;
+            }
+            return Ok(());
+        }
+    };
+    process_address(bridge, id, address, message.message_type, message.throttle).await
+}
+
+/// Checks and forwards a command for one address. Each of the client's multi-throttles is
+/// sent to JMRI as one assigned to it alone. Acquiring an address the client already holds on
+/// another multi-throttle moves it over, acquiring one another client holds takes it from them
+/// if the login may steal, and anything else is sent on the multi-throttle the address is held
+/// on whatever the command said.
+async fn process_address(
+    bridge: &Bridge,
+    id: Uuid,
+    address: LocoAddress,
+    message_type: WiMessageType,
+    throttle: ThrottleId,
+) -> Result<(), CommandError> {
+    let mut message = WiMessage::new(address, message_type).with_throttle(throttle);
+    // Released on JMRI before the command, on multi-throttles it's moved or stolen from
+    let mut releases = Vec::new();
+    {
+        let mut clients = bridge.clients.write().await;
+        let holders: Vec<Uuid> = clients
+            .iter()
+            .filter(|(other, client)| **other != id && client.addresses.contains_key(&address))
+            .map(|(other, _)| *other)
+            .collect();
+        let taken: BTreeSet<ThrottleId> = clients
+            .iter()
+            .filter(|(other, _)| **other != id)
+            .flat_map(|(_, client)| client.jmri_throttles())
+            .collect();
+        let Some(client) = clients.get_mut(&id) else {
+            return Ok(());
+        };
+        let permissions = &client.login.permissions;
+
+        if permissions.view_only {
+            return Err(CommandError::ViewOnly);
+        }
+        if !client.rate_limiter.check(Instant::now()) {
+            return Err(CommandError::RateLimited);
+        }
+        if !bridge.jmri_connected.load(Ordering::Relaxed) {
+            return Err(CommandError::JmriDisconnected);
+        }
 
-    if message.message_type == WiMessageType::AddAddress {
-        if let Some(client) = CLIENTS.write().await.get_mut(&id) {
-            client.addresses.insert(message.address);
+        if message_type == WiMessageType::AddAddress {
+            if !permissions.may_acquire(address) {
+                return Err(CommandError::AddressNotPermitted(address));
+            }
+            if !holders.is_empty() && !permissions.steal {
+                return Err(CommandError::AddressInUse(address));
+            }
+            message.throttle = client
+                .assign_throttle(throttle, &taken)
+                .ok_or(CommandError::NoFreeThrottle)?;
+            if let Some(held_on) = client.add_address(address, message.throttle) {
+                releases.push(held_on);
+            }
+            for holder in holders {
+                let Some(other) = clients.get_mut(&holder) else {
+                    continue;
+                };
+                let Some(held_on) = other.addresses.get(&address).copied() else {
+                    continue;
+                };
+                info!("Client '{id}' stole address {address} from client '{holder}'");
+                other.update(WiMessage::new(address, RemoveAddress).with_throttle(held_on));
+                other.remove_address(address);
+                releases.push(held_on);
+            }
+        } else if let Some(held_on) = client.addresses.get(&address) {
+            message.throttle = *held_on;
+            if message_type == WiMessageType::RemoveAddress {
+                client.update(message);
+                client.remove_address(address);
+            }
+        } else {
+            return Err(CommandError::NotOwner(address));
         }
-    } else if message.message_type == WiMessageType::RemoveAddress {
-        if let Some(client) = CLIENTS.write().await.get_mut(&id) {
-            client
-                .sender
-                .send(
-                    serde_json::to_string(&WiMessage::new(message.address, RemoveAddress)).unwrap(),
-                )
-                .unwrap();
-            client.addresses.remove(&message.address);
+    }
+
+    releases.sort();
+    releases.dedup();
+    for held_on in releases
+        .into_iter()
+        .filter(|held_on| *held_on != message.throttle)
+    {
+        let release = WiMessage::new(address, RemoveAddress).with_throttle(held_on);
+        bridge.velocity_coalescer.submit(release).await?;
+    }
+    bridge.velocity_coalescer.submit(message).await
+}
+
+/// Acks are only sent when the client asked for one, errors are always sent.
+async fn reply(
+    bridge: &Bridge,
+    id: Uuid,
+    request_id: Option<RequestId>,
+    result: Result<(), CommandError>,
+) {
+    let reply = match (request_id, result) {
+        (_, Err(error)) => ServerMessage::Error { request_id, error },
+        (Some(request_id), Ok(())) => ServerMessage::Ack { request_id },
+        (None, Ok(())) => return,
+    };
+    if let Some(client) = bridge.clients.read().await.get(&id) {
+        client.send(reply);
+    }
+}
+
+/// Tries to pull the request ID out of a message that failed to deserialize,
+/// so the client can still match the error to what it sent.
+fn salvage_request_id(encoding: Encoding, message: &[u8]) -> Option<RequestId> {
+    #[derive(Deserialize)]
+    struct RequestIdOnly {
+        request_id: Option<RequestId>,
+    }
+    encoding.decode::<RequestIdOnly>(message).ok()?.request_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::Config;
+    use crate::client::{Client, SharedMessage};
+    use jmri_throttle_rs::message::{AddressKind, Direction, LocoAddress};
+    use std::sync::Arc;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    async fn connected_client() -> (Arc<Bridge>, Uuid, Receiver<SharedMessage>) {
+        let bridge = Bridge::new(Config::default());
+        bridge.jmri_connected.store(true, Ordering::Relaxed);
+        let id = Uuid::new_v4();
+        let (tx, rx) = mpsc::channel(8);
+        bridge
+            .clients
+            .write()
+            .await
+            .insert(id, Client::new(id, tx, 100.0));
+        (bridge, id, rx)
+    }
+
+    /// The next message queued for a client.
+    async fn recv(rx: &mut Receiver<SharedMessage>) -> Option<ServerMessage> {
+        rx.recv().await.map(|message| message.message().clone())
+    }
+
+    fn text(json: &str) -> Message {
+        Message::text(json)
+    }
+
+    #[tokio::test]
+    async fn acquire_is_forwarded_and_acked() {
+        let (bridge, id, mut rx) = connected_client().await;
+        let message = text(
+            r#"{"request_id":1,"message_type":"AddAddress","address":{"number":3,"kind":"Short"}}"#,
+        );
+        handle_message(&bridge, id, Encoding::Json, message).await;
+
+        let line = bridge.to_jmri.rx.lock().await.recv().await.unwrap();
+        assert_eq!(line, "MT+S3<;>S3");
+        assert!(matches!(
+            recv(&mut rx).await,
+            Some(ServerMessage::Ack { request_id: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn commands_for_unowned_addresses_are_rejected() {
+        let (bridge, id, mut rx) = connected_client().await;
+        let message = text(
+            r#"{"request_id":2,"message_type":{"Velocity":5},"address":{"number":3,"kind":"Short"}}"#,
+        );
+        handle_message(&bridge, id, Encoding::Json, message).await;
+
+        assert!(bridge.to_jmri.rx.lock().await.try_recv().is_err());
+        assert!(matches!(
+            recv(&mut rx).await,
+            Some(ServerMessage::Error {
+                request_id: Some(2),
+                error: CommandError::NotOwner(address)
+            }) if address == LocoAddress::short(3).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn parse_errors_keep_the_request_id() {
+        let (bridge, id, mut rx) = connected_client().await;
+        handle_message(&bridge, id, Encoding::Json, text(r#"{"request_id":9}"#)).await;
+
+        assert!(matches!(
+            recv(&mut rx).await,
+            Some(ServerMessage::Error {
+                request_id: Some(9),
+                error: CommandError::Parse(_)
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn wildcards_only_reach_the_clients_own_addresses() {
+        let (bridge, id, mut rx) = connected_client().await;
+        let other = Uuid::new_v4();
+        let (tx, _other_rx) = mpsc::channel(8);
+        let mut holder = Client::new(other, tx, 100.0);
+        holder
+            .addresses
+            .insert(LocoAddress::short(4).unwrap(), ThrottleId::DEFAULT);
+        bridge.clients.write().await.insert(other, holder);
+
+        for address in [
+            LocoAddress::long(5).unwrap(),
+            LocoAddress::short(3).unwrap(),
+        ] {
+            let message = WiMessage::new(address, WiMessageType::AddAddress);
+            process_message(&bridge, id, message).await.unwrap();
         }
+        let mut to_jmri = bridge.to_jmri.rx.lock().await;
+        to_jmri.recv().await.unwrap();
+        to_jmri.recv().await.unwrap();
+
+        // The other client has T on JMRI's connection, so this one's T is sent as A
+        let stop = WiMessage::new(Target::All, WiMessageType::EmergencyStop);
+        handle_command(&bridge, id, Some(1), stop).await;
+        assert_eq!(to_jmri.recv().await.unwrap(), "MAAS3<;>X");
+        assert_eq!(to_jmri.recv().await.unwrap(), "MAAL5<;>X");
+        assert!(to_jmri.try_recv().is_err());
+        assert!(matches!(
+            recv(&mut rx).await,
+            Some(ServerMessage::Ack { request_id: 1 })
+        ));
+
+        let acquire = WiMessage::new(Target::All, WiMessageType::AddAddress);
+        assert!(matches!(
+            process_message(&bridge, id, acquire).await,
+            Err(CommandError::Parse(_))
+        ));
     }
 
-    TO_JMRI.tx.read().await.send(message.to_string()).unwrap();
+    #[tokio::test]
+    async fn addresses_stay_on_their_multi_throttle() {
+        let (bridge, id, _rx) = connected_client().await;
+        let (a, b) = (
+            ThrottleId::try_from('A').unwrap(),
+            ThrottleId::try_from('B').unwrap(),
+        );
+        let (s3, s5) = (
+            LocoAddress::short(3).unwrap(),
+            LocoAddress::short(5).unwrap(),
+        );
+        let acquire = |address, throttle| {
+            WiMessage::new(address, WiMessageType::AddAddress).with_throttle(throttle)
+        };
+        process_message(&bridge, id, acquire(s3, a)).await.unwrap();
+        process_message(&bridge, id, acquire(s5, b)).await.unwrap();
+        let mut to_jmri = bridge.to_jmri.rx.lock().await;
+        assert_eq!(to_jmri.recv().await.unwrap(), "MA+S3<;>S3");
+        assert_eq!(to_jmri.recv().await.unwrap(), "MB+S5<;>S5");
+
+        // Sent on the throttle holding the address, whatever the command said
+        let reverse = WiMessage::new(s3, WiMessageType::Direction(Direction::Reverse));
+        process_message(&bridge, id, reverse).await.unwrap();
+        assert_eq!(to_jmri.recv().await.unwrap(), "MAAS3<;>R0");
+
+        let stop = WiMessage::new(Target::All, WiMessageType::EmergencyStop).with_throttle(b);
+        process_message(&bridge, id, stop).await.unwrap();
+        assert_eq!(to_jmri.recv().await.unwrap(), "MBAS5<;>X");
+        assert!(to_jmri.try_recv().is_err());
+
+        // Acquiring on another throttle moves the address over
+        process_message(&bridge, id, acquire(s3, b)).await.unwrap();
+        assert_eq!(to_jmri.recv().await.unwrap(), "MA-S3<;>S3");
+        assert_eq!(to_jmri.recv().await.unwrap(), "MB+S3<;>S3");
+        assert_eq!(bridge.throttles().await, [b, ThrottleId::DEFAULT].into());
+    }
+
+    #[tokio::test]
+    async fn stealing_releases_the_previous_holder() {
+        let (bridge, id, _rx) = connected_client().await;
+        let other = Uuid::new_v4();
+        let (tx, mut other_rx) = mpsc::channel(8);
+        let mut holder = Client::new(other, tx, 100.0);
+        let (s4, a) = (
+            LocoAddress::short(4).unwrap(),
+            ThrottleId::try_from('A').unwrap(),
+        );
+        holder.addresses.insert(s4, a);
+        bridge.clients.write().await.insert(other, holder);
+
+        let acquire = WiMessage::new(s4, WiMessageType::AddAddress);
+        process_message(&bridge, id, acquire).await.unwrap();
+        let mut to_jmri = bridge.to_jmri.rx.lock().await;
+        assert_eq!(to_jmri.recv().await.unwrap(), "MA-S4<;>S4");
+        assert_eq!(to_jmri.recv().await.unwrap(), "MT+S4<;>S4");
+        assert!(matches!(
+            recv(&mut other_rx).await,
+            Some(ServerMessage::Update(release))
+                if release == WiMessage::new(s4, RemoveAddress).with_throttle(a)
+        ));
+
+        let clients = bridge.clients.read().await;
+        assert!(clients[&other].addresses.is_empty());
+        assert_eq!(clients[&id].addresses.get(&s4), Some(&ThrottleId::DEFAULT));
+    }
+
+    #[tokio::test]
+    async fn permissions_are_enforced() {
+        let (bridge, id, mut rx) = connected_client().await;
+        let other = Uuid::new_v4();
+        let (tx, _other_rx) = mpsc::channel(8);
+        let mut holder = Client::new(other, tx, 100.0);
+        holder
+            .addresses
+            .insert(LocoAddress::short(4).unwrap(), ThrottleId::DEFAULT);
+        bridge.clients.write().await.insert(other, holder);
+
+        {
+            let mut clients = bridge.clients.write().await;
+            let permissions = &mut clients.get_mut(&id).unwrap().login.permissions;
+            permissions.addresses = vec![(AddressKind::Short, 1, 99)];
+            permissions.steal = false;
+        }
+
+        for (address, expected) in [
+            (
+                LocoAddress::short(100).unwrap(),
+                CommandError::AddressNotPermitted(LocoAddress::short(100).unwrap()),
+            ),
+            (
+                LocoAddress::long(4).unwrap(),
+                CommandError::AddressNotPermitted(LocoAddress::long(4).unwrap()),
+            ),
+            (
+                LocoAddress::short(4).unwrap(),
+                CommandError::AddressInUse(LocoAddress::short(4).unwrap()),
+            ),
+        ] {
+            handle_command(
+                &bridge,
+                id,
+                None,
+                WiMessage::new(address, WiMessageType::AddAddress),
+            )
+            .await;
+            assert!(
+                matches!(recv(&mut rx).await, Some(ServerMessage::Error { error, .. }) if error == expected)
+            );
+        }
+
+        bridge
+            .clients
+            .write()
+            .await
+            .get_mut(&id)
+            .unwrap()
+            .login
+            .permissions
+            .view_only = true;
+        handle_command(
+            &bridge,
+            id,
+            None,
+            WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+        )
+        .await;
+        assert!(matches!(
+            recv(&mut rx).await,
+            Some(ServerMessage::Error {
+                error: CommandError::ViewOnly,
+                ..
+            })
+        ));
+        assert!(bridge.to_jmri.rx.lock().await.try_recv().is_err());
+    }
 }
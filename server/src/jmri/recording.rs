@@ -0,0 +1,249 @@
+//! Records the raw WiThrottle traffic with JMRI to a file, one line per entry:
+//! `<unix millis>\t<direction>\t<line>` where the direction is `<` from JMRI and `>` to JMRI.
+//! A recording can be replayed through the parser and client fan-out with [replay].
+
+use crate::bridge::Bridge;
+use crate::jmri::handle_jmri_line;
+use log::{debug, error, info};
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Traffic {
+    FromJmri,
+    ToJmri,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordedLine {
+    pub millis: u64,
+    pub traffic: Traffic,
+    pub line: String,
+}
+
+impl Display for RecordedLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let direction = match self.traffic {
+            Traffic::FromJmri => '<',
+            Traffic::ToJmri => '>',
+        };
+        write!(f, "{}\t{direction}\t{}", self.millis, self.line)
+    }
+}
+
+impl FromStr for RecordedLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(3, '\t');
+        let (Some(millis), Some(direction), Some(line)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("Incomplete recorded line: {s}"));
+        };
+        let millis = millis
+            .parse()
+            .map_err(|e| format!("Bad timestamp: {e}, Line: {s}"))?;
+        let traffic = match direction {
+            "<" => Traffic::FromJmri,
+            ">" => Traffic::ToJmri,
+            _ => return Err(format!("Bad direction: {direction}, Line: {s}")),
+        };
+        Ok(RecordedLine {
+            millis,
+            traffic,
+            line: line.to_string(),
+        })
+    }
+}
+
+/// Writes recorded lines on a thread of its own, so a slow disk can't hold up the bridge.
+/// Dropping it waits for everything recorded so far to be written.
+pub struct Recorder {
+    entries: Option<Sender<RecordedLine>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Opens `path` for appending, so restarts add to the same recording.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (entries, received) = mpsc::channel::<RecordedLine>();
+        let writer = thread::Builder::new()
+            .name("jmri-recorder".to_string())
+            .spawn(move || {
+                let mut file = LineWriter::new(file);
+                for entry in received {
+                    if let Err(e) = writeln!(file, "{entry}") {
+                        error!("Error writing JMRI recording: {e}");
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self {
+            entries: Some(entries),
+            writer: Some(writer),
+        })
+    }
+
+    /// Records `lines`, which may hold several newline separated WiThrottle lines. Heartbeats,
+    /// which would otherwise make up most of a recording, are left out.
+    pub fn record(&self, traffic: Traffic, lines: &str) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        for line in lines.lines() {
+            if line.is_empty() || is_heartbeat(traffic, line) {
+                continue;
+            }
+            let entry = RecordedLine {
+                millis,
+                traffic,
+                line: line.to_string(),
+            };
+            // Only fails once the writer has given up, which it has logged already
+            if entries.send(entry).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.entries = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The bridge sends the throttle's name as its heartbeat, and JMRI answers with `*` and its
+/// heartbeat interval.
+fn is_heartbeat(traffic: Traffic, line: &str) -> bool {
+    match traffic {
+        Traffic::ToJmri => line.starts_with('N'),
+        Traffic::FromJmri => line.starts_with('*'),
+    }
+}
+
+pub fn read_recording(path: &Path) -> io::Result<Vec<RecordedLine>> {
+    let lines = std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            RecordedLine::from_str(line)
+                .map_err(|e| error!("Skipping recorded line: {e}"))
+                .ok()
+        })
+        .collect();
+    Ok(lines)
+}
+
+/// Feeds the lines JMRI sent in a recording back through [handle_jmri_line]. With `realtime`
+/// the original gaps between lines are kept, otherwise they're replayed as fast as possible.
+pub async fn replay(bridge: &Bridge, path: &Path, realtime: bool) -> io::Result<()> {
+    let recording = read_recording(path)?;
+    info!("Replaying {} recorded lines from {path:?}", recording.len());
+
+    let mut last = recording.first().map_or(0, |line| line.millis);
+    for recorded in recording {
+        if realtime {
+            sleep(Duration::from_millis(recorded.millis.saturating_sub(last))).await;
+            last = recorded.millis;
+        }
+        match recorded.traffic {
+            Traffic::FromJmri => handle_jmri_line(bridge, &recorded.line).await,
+            Traffic::ToJmri => debug!("Recorded message to JMRI: {}", recorded.line),
+        }
+    }
+    Ok(())
+}
+
+/// Stands in for [crate::jmri::jmri_conn] when replaying: clients can connect and acquire
+/// addresses as usual while the recording plays in real time, anything they send is only logged.
+pub async fn replay_session(bridge: Arc<Bridge>, path: PathBuf) -> io::Result<()> {
+    bridge.jmri_connected.store(true, Ordering::Relaxed);
+    let drain = {
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            let mut to_jmri = bridge.to_jmri.rx.lock().await;
+            while let Some(line) = to_jmri.recv().await {
+                info!("Replaying, not sending message to JMRI: {line}");
+            }
+        })
+    };
+
+    let result = replay(&bridge, &path, true).await;
+    drain.abort();
+    bridge.jmri_connected.store(false, Ordering::Relaxed);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::Config;
+    use crate::client::{Client, SharedMessage};
+    use jmri_throttle_rs::message::{LocoAddress, ThrottleId, Velocity, WiMessageType};
+    use jmri_throttle_rs::protocol::ServerMessage;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[test]
+    fn recorded_line_round_trip() {
+        let line = RecordedLine {
+            millis: 1700000000123,
+            traffic: Traffic::FromJmri,
+            line: "MTAS3<;>V40".to_string(),
+        };
+        assert_eq!(line.to_string(), "1700000000123\t<\tMTAS3<;>V40");
+        assert_eq!(RecordedLine::from_str(&line.to_string()), Ok(line));
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("jmri-recording-{}.txt", Uuid::new_v4()));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(Traffic::ToJmri, "MT+S3<;>S3\nMTAS3<;>V40");
+        recorder.record(Traffic::ToJmri, "NThrottle");
+        recorder.record(Traffic::FromJmri, "*10");
+        recorder.record(Traffic::FromJmri, "MTAS3<;>V40");
+        drop(recorder);
+
+        let recording = read_recording(&path).unwrap();
+        assert_eq!(recording.len(), 3);
+        assert_eq!(recording[1].traffic, Traffic::ToJmri);
+        assert_eq!(recording[1].line, "MTAS3<;>V40");
+
+        let bridge = Bridge::new(Config::default());
+        let id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut client = Client::new(id, tx, 100.0);
+        client
+            .addresses
+            .insert(LocoAddress::short(3).unwrap(), ThrottleId::DEFAULT);
+        bridge.clients.write().await.insert(id, client);
+
+        replay(&bridge, &path, false).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            rx.recv().await.as_ref().map(SharedMessage::message),
+            Some(ServerMessage::Update(m)) if m.message_type == WiMessageType::Velocity(Velocity::try_from(40).unwrap())
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+#![forbid(unsafe_code)]
+pub mod admin;
+pub mod auth;
+pub mod bridge;
+pub mod client;
+pub mod discovery;
+pub mod health;
+pub mod jmri;
+pub mod metrics;
+pub mod mock_jmri;
+mod rate_limit;
+pub mod rest;
+pub mod routes;
+pub mod tls;
+pub mod withrottle;
+pub mod ws;
+
+pub use bridge::{Bridge, Config};
@@ -1,68 +1,69 @@
-#[forbid(unsafe_code)]
-mod client;
-mod jmri;
-mod ws;
-
-use crate::jmri::jmri_conn;
-use crate::ws::handle_connection;
 use futures::future::join;
+use futures::FutureExt;
 use log::error;
-use once_cell::sync::Lazy;
+use mdns_sd::ServiceDaemon;
+use server::jmri::jmri_conn;
+use server::jmri::recording::replay_session;
+use server::routes::routes;
+use server::tls::Identity;
+use server::{discovery, withrottle, Bridge, Config};
 use std::error::Error;
-use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{mpsc, Notify, RwLock};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::http::StatusCode;
-use warp::Filter;
-
-struct JmriChannel {
-    pub tx: RwLock<UnboundedSender<String>>,
-    pub rx: RwLock<UnboundedReceiverStream<String>>,
-}
+use tokio::net::TcpListener;
 
-static TO_JMRI: Lazy<JmriChannel> = make_chan();
-static FROM_JMRI: Lazy<JmriChannel> = make_chan();
-
-const fn make_chan() -> Lazy<JmriChannel> {
-    Lazy::new(|| {
-        let (tx, rx) = mpsc::unbounded_channel::<String>();
-        let tx = RwLock::new(tx);
-        let rx = RwLock::new(UnboundedReceiverStream::new(rx));
-        JmriChannel { tx, rx }
-    })
-}
-
-static TIME: Lazy<RwLock<i64>> = Lazy::new(|| RwLock::new(0));
+const HTTP_PORT: u16 = 4000;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
-    let jmri_notify = Arc::new(Notify::new());
-    let jmri_handle = {
-        let jmri_notify = jmri_notify.clone();
+    let bridge = Bridge::new(Config::from_env());
+    // Checked before connecting to JMRI so a bad certificate fails straight away
+    let identity = Identity::from_config(&bridge.config).unwrap_or_else(|e| {
+        error!("Error loading TLS certificate: {e}");
+        std::process::exit(1);
+    });
+
+    // Served while JMRI is still being connected to, readiness says when it is
+    let jmri_handle = if let Some(path) = bridge.config.replay_path.clone() {
+        let bridge = bridge.clone();
         tokio::spawn(async move {
-            if let Err(e) = jmri_conn(jmri_notify).await {
-                error!("Error on jmri_conn: {e}");
+            if let Err(e) = replay_session(bridge, path).await {
+                error!("Error replaying recording: {e}");
+                std::process::exit(1);
             }
         })
+    } else {
+        tokio::spawn(jmri_conn(bridge.clone()))
     };
 
-    // Lets us know we're connected to JMRI and can continue
-    jmri_notify.notified().await;
-
-    let health = warp::path!("health")
-        .and(warp::get())
-        .map(|| warp::reply::with_status("Healthy", StatusCode::OK));
-
-    let ws = warp::path("ws")
-        .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(handle_connection));
+    if let Some(addr) = &bridge.config.withrottle_listen {
+        let listener = TcpListener::bind(addr).await?;
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            if let Err(e) = withrottle::serve(bridge, listener).await {
+                error!("Error on WiThrottle listener: {e}");
+            }
+        });
+    }
 
-    let routes = health.or(ws);
+    // Kept alive for as long as the server runs, dropping it stops the announcements
+    let _mdns = if bridge.config.advertise {
+        let daemon = ServiceDaemon::new()?;
+        let name = &bridge.config.throttle_name;
+        if let Err(e) = discovery::advertise(&daemon, name, HTTP_PORT, identity.is_some()) {
+            error!("Error advertising over mDNS: {e}");
+        }
+        Some(daemon)
+    } else {
+        None
+    };
 
-    let warp_handle = warp::serve(routes).run(([0, 0, 0, 0], 4000));
+    let server = warp::serve(routes(bridge));
+    let addr = ([0, 0, 0, 0], HTTP_PORT);
+    let warp_handle = match identity {
+        Some(Identity { cert, key }) => server.tls().cert(cert).key(key).run(addr).boxed(),
+        None => server.run(addr).boxed(),
+    };
 
     let _ = join(jmri_handle, warp_handle).await;
 
@@ -0,0 +1,171 @@
+//! Prometheus metrics served at `/metrics`. Counters are bumped where things happen, gauges
+//! describing the current clients are read from the bridge on every scrape.
+
+use crate::bridge::Bridge;
+use prometheus::{
+    core::Collector, Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashSet;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Lines exchanged with JMRI, by `direction` (`to_jmri` or `from_jmri`).
+    pub jmri_lines: IntCounterVec,
+    /// Lines or messages that couldn't be parsed, by `source` (`jmri` or `client`).
+    pub parse_errors: IntCounterVec,
+    pub jmri_connects: IntCounter,
+    pub heartbeat_misses: IntCounter,
+    pub client_connections: IntCounter,
+    /// Commands from clients, by `result` (`accepted` or `rejected`).
+    pub client_commands: IntCounterVec,
+    clients: IntGauge,
+    acquired_addresses: IntGauge,
+    client_queue_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("jmri_bridge".into()), None).unwrap();
+        let metrics = Self {
+            jmri_lines: IntCounterVec::new(
+                Opts::new("jmri_lines_total", "Lines exchanged with JMRI"),
+                &["direction"],
+            )
+            .unwrap(),
+            parse_errors: IntCounterVec::new(
+                Opts::new(
+                    "parse_errors_total",
+                    "Lines or messages that couldn't be parsed",
+                ),
+                &["source"],
+            )
+            .unwrap(),
+            jmri_connects: IntCounter::new(
+                "jmri_connects_total",
+                "Attempts to connect to JMRI, the first one and every reconnect",
+            )
+            .unwrap(),
+            heartbeat_misses: IntCounter::new(
+                "heartbeat_misses_total",
+                "Heartbeats that couldn't be sent to JMRI in time or that JMRI didn't answer",
+            )
+            .unwrap(),
+            client_connections: IntCounter::new(
+                "client_connections_total",
+                "WebSocket and WiThrottle connections let in",
+            )
+            .unwrap(),
+            client_commands: IntCounterVec::new(
+                Opts::new("client_commands_total", "Commands received from clients"),
+                &["result"],
+            )
+            .unwrap(),
+            clients: IntGauge::new("clients", "Connected clients").unwrap(),
+            acquired_addresses: IntGauge::new(
+                "acquired_addresses",
+                "Addresses held by at least one client",
+            )
+            .unwrap(),
+            client_queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "client_queue_depth",
+                    "Messages waiting to be sent to each client",
+                ),
+                &["client"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 9] = [
+            Box::new(metrics.jmri_lines.clone()),
+            Box::new(metrics.parse_errors.clone()),
+            Box::new(metrics.jmri_connects.clone()),
+            Box::new(metrics.heartbeat_misses.clone()),
+            Box::new(metrics.client_connections.clone()),
+            Box::new(metrics.client_commands.clone()),
+            Box::new(metrics.clients.clone()),
+            Box::new(metrics.acquired_addresses.clone()),
+            Box::new(metrics.client_queue_depth.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// Everything in Prometheus' text format, with the client gauges read from `bridge`.
+    pub async fn render(&self, bridge: &Bridge) -> String {
+        {
+            let clients = bridge.clients.read().await;
+            // REST API users have no connection to count
+            let connected = clients.values().filter(|client| client.sender.is_some());
+            self.clients.set(connected.count() as i64);
+            let addresses = clients
+                .values()
+                .flat_map(|client| client.addresses.keys())
+                .collect::<HashSet<_>>();
+            self.acquired_addresses.set(addresses.len() as i64);
+
+            // Reset so disconnected clients disappear
+            self.client_queue_depth.reset();
+            for client in clients.values() {
+                if let Some(sender) = &client.sender {
+                    let depth = sender.max_capacity() - sender.capacity();
+                    self.client_queue_depth
+                        .with_label_values(&[&client.id.to_string()])
+                        .set(depth as i64);
+                }
+            }
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::Config;
+    use crate::client::Client;
+    use jmri_throttle_rs::message::{LocoAddress, ThrottleId};
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn renders_counters_and_client_gauges() {
+        let bridge = Bridge::new(Config::default());
+        let id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        let mut client = Client::new(id, tx, 100.0);
+        client
+            .addresses
+            .insert(LocoAddress::short(3).unwrap(), ThrottleId::DEFAULT);
+        client.send(jmri_throttle_rs::protocol::ServerMessage::Ack { request_id: 1 });
+        bridge.clients.write().await.insert(id, client);
+        bridge
+            .metrics
+            .jmri_lines
+            .with_label_values(&["from_jmri"])
+            .inc();
+
+        let text = bridge.metrics.render(&bridge).await;
+        assert!(text.contains("jmri_bridge_clients 1"));
+        assert!(text.contains("jmri_bridge_acquired_addresses 1"));
+        assert!(text.contains(&format!(
+            "jmri_bridge_client_queue_depth{{client=\"{id}\"}} 1"
+        )));
+        assert!(text.contains("jmri_bridge_jmri_lines_total{direction=\"from_jmri\"} 1"));
+    }
+}
@@ -0,0 +1,215 @@
+//! A small in-process stand-in for JMRI's WiThrottle server, so the bridge can be exercised
+//! without a real layout. It speaks enough of the protocol for the bridge: the handshake,
+//! roster and turnouts, throwing turnouts, acquire/release with the state JMRI reports back,
+//! speed/direction/function echoes and the fast clock. Anything else can be scripted with [MockJmri::send].
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, error};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const FUNCTION_COUNT: u8 = 29;
+
+pub struct MockJmri {
+    addr: SocketAddr,
+    to_throttles: broadcast::Sender<ToThrottle>,
+    received: Mutex<mpsc::UnboundedReceiver<String>>,
+}
+
+impl MockJmri {
+    /// Starts listening on `addr`, use port 0 to let the OS pick one.
+    pub async fn start(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let (to_throttles, _) = broadcast::channel(64);
+        let (received_tx, received) = mpsc::unbounded_channel();
+
+        let broadcast = to_throttles.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        debug!("Mock JMRI connection from {peer}");
+                        let connection = Connection::default();
+                        tokio::spawn(connection.run(
+                            stream,
+                            broadcast.subscribe(),
+                            received_tx.clone(),
+                        ));
+                    }
+                    Err(e) => error!("Mock JMRI accept error: {e}"),
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            to_throttles,
+            received: Mutex::new(received),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends a raw line to every connected throttle.
+    pub fn send(&self, line: impl Into<String>) {
+        // No receivers just means nobody is connected yet
+        let _ = self.to_throttles.send(ToThrottle::Line(line.into()));
+    }
+
+    /// Closes every connection, like JMRI going away. New connections are still accepted.
+    pub fn disconnect(&self) {
+        let _ = self.to_throttles.send(ToThrottle::Disconnect);
+    }
+
+    pub fn set_clock(&self, time: i64) {
+        self.send(format!("PFT{time}<;>1.0"));
+    }
+
+    /// The next line a throttle sent, skipping heartbeats and the name sent again as one.
+    pub async fn next_line(&self) -> Option<String> {
+        self.received.lock().await.recv().await
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ToThrottle {
+    Line(String),
+    Disconnect,
+}
+
+#[derive(Default)]
+struct Connection {
+    // The throttle's name, which the bridge sends again as a heartbeat
+    name: Option<String>,
+    // Function states per acquired address key, e.g. "S3" or "L128"
+    functions: HashMap<String, HashSet<u8>>,
+}
+
+impl Connection {
+    async fn run(
+        mut self,
+        stream: TcpStream,
+        mut broadcast: broadcast::Receiver<ToThrottle>,
+        received: mpsc::UnboundedSender<String>,
+    ) {
+        let mut framed = Framed::new(stream, LinesCodec::new());
+        for line in handshake() {
+            if framed.send(line).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            let replies = tokio::select! {
+                line = framed.next() => match line {
+                    Some(Ok(line)) => {
+                        let line = line.trim();
+                        if line.is_empty() || line == "*" {
+                            continue;
+                        }
+                        if self.name.as_deref() != Some(line) {
+                            let _ = received.send(line.to_string());
+                        }
+                        self.respond(line)
+                    }
+                    _ => return,
+                },
+                Ok(to_throttle) = broadcast.recv() => match to_throttle {
+                    ToThrottle::Line(line) => vec![line],
+                    ToThrottle::Disconnect => return,
+                },
+            };
+            for reply in replies {
+                if framed.send(reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respond(&mut self, line: &str) -> Vec<String> {
+        if line.starts_with('N') {
+            self.name = Some(line.to_string());
+            // Heartbeat interval in seconds
+            return vec!["*10".into()];
+        }
+        // Turnouts change straight away and report their new state
+        if let Some(command) = line.strip_prefix("PTA") {
+            let mut chars = command.chars();
+            let state = match chars.next() {
+                Some('C') => '2',
+                Some('T') => '4',
+                _ => return Vec::new(),
+            };
+            return vec![format!("PTA{state}{}", chars.as_str())];
+        }
+        let Some(rest) = line.strip_prefix('M') else {
+            return Vec::new();
+        };
+        let mut chars = rest.chars();
+        let (Some(throttle), Some(action)) = (chars.next(), chars.next()) else {
+            return Vec::new();
+        };
+        let (key, command) = chars
+            .as_str()
+            .split_once("<;>")
+            .unwrap_or((chars.as_str(), ""));
+
+        match action {
+            '+' => {
+                self.functions.insert(key.to_string(), HashSet::new());
+                let mut lines = vec![
+                    format!("M{throttle}+{key}<;>"),
+                    format!(r"M{throttle}L{key}<;>]\[Headlight]\[Bell]\[Horn"),
+                ];
+                lines.extend((0..FUNCTION_COUNT).map(|f| format!("M{throttle}A{key}<;>F0{f}")));
+                lines.push(format!("M{throttle}A{key}<;>V0"));
+                lines.push(format!("M{throttle}A{key}<;>R1"));
+                lines.push(format!("M{throttle}A{key}<;>s1"));
+                lines
+            }
+            '-' => {
+                self.functions.remove(key);
+                vec![format!("M{throttle}-{key}<;>")]
+            }
+            'A' if command.starts_with('V') || command.starts_with('R') => {
+                vec![format!("M{throttle}A{key}<;>{command}")]
+            }
+            'A' if command.starts_with("F1") => {
+                let Ok(function) = command[2..].parse::<u8>() else {
+                    return Vec::new();
+                };
+                // Like JMRI, a press toggles the function and reports the new state
+                let functions = self.functions.entry(key.to_string()).or_default();
+                let state = if functions.remove(&function) {
+                    0
+                } else {
+                    functions.insert(function);
+                    1
+                };
+                vec![format!("M{throttle}A{key}<;>F{state}{function}")]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn handshake() -> Vec<String> {
+    vec![
+        "VN2.0".into(),
+        "HTJMRI".into(),
+        "HtJMRI v5.4 Mock Railroad".into(),
+        r"RL2]\[Mock Switcher}|{3}|{S]\[Mock Mainline}|{4014}|{L".into(),
+        r"PTL]\[LT1}|{Yard Lead}|{2]\[LT2}|{Mainline Siding}|{4".into(),
+        "PPA1".into(),
+        "PFT0<;>1.0".into(),
+        "PW12080".into(),
+    ]
+}
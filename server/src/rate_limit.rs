@@ -0,0 +1,48 @@
+use std::time::Instant;
+
+/// A token bucket, refilled continuously at `rate` tokens per second up to `rate` tokens.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available, returning whether the message may go through.
+    pub fn check(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(2.0);
+        let now = Instant::now();
+        assert!(limiter.check(now));
+        assert!(limiter.check(now));
+        assert!(!limiter.check(now));
+        assert!(limiter.check(now + Duration::from_millis(500)));
+        assert!(!limiter.check(now + Duration::from_millis(500)));
+    }
+}
@@ -0,0 +1,328 @@
+//! A JSON API under `/api` for scripts and automation. Each user's commands are issued as one
+//! client, so addresses have to be acquired with `POST /api/locos/<address>` before they can be
+//! driven, exactly like a WebSocket throttle. That client is started by the first acquire and
+//! ends once the user has released everything. When authentication is enabled requests log in
+//! with an `Authorization: Bearer <PIN or token>` header.
+//!
+//! Addresses in paths are written `S3` or `L3`. A bare number is taken as short or long
+//! depending on the configured `short_address_limit`.
+
+use crate::auth::Login;
+use crate::bridge::{Bridge, LocoState};
+use crate::jmri::process_message;
+use crate::routes::with_bridge;
+use jmri_throttle_rs::layout::{format_turnout_command, TurnoutState};
+use jmri_throttle_rs::message::{
+    ClockRate, Direction, Function, LocoAddress, Velocity, WiMessage, WiMessageType,
+};
+use jmri_throttle_rs::protocol::CommandError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::{json, with_status, Response};
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Serialize, Debug)]
+pub struct Loco {
+    pub address: LocoAddress,
+    #[serde(flatten)]
+    pub state: LocoState,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Clock {
+    pub time: i64,
+    pub rate: ClockRate,
+}
+
+#[derive(Deserialize, Debug)]
+struct SetVelocity {
+    velocity: Velocity,
+}
+
+#[derive(Deserialize, Debug)]
+struct SetDirection {
+    direction: Direction,
+}
+
+#[derive(Deserialize, Debug)]
+struct SetTurnout {
+    state: TurnoutState,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    error: String,
+}
+
+pub fn api(bridge: Arc<Bridge>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let locos = warp::path!("api" / "locos")
+        .and(warp::get())
+        .and(authenticated(bridge.clone()))
+        .and_then(list_locos);
+
+    let acquire = warp::path!("api" / "locos" / String)
+        .and(warp::post())
+        .and(authenticated(bridge.clone()))
+        .and_then(|address, bridge, login| {
+            command(bridge, login, address, WiMessageType::AddAddress)
+        });
+
+    let release = warp::path!("api" / "locos" / String)
+        .and(warp::delete())
+        .and(authenticated(bridge.clone()))
+        .and_then(|address, bridge, login| {
+            command(bridge, login, address, WiMessageType::RemoveAddress)
+        });
+
+    let speed = warp::path!("api" / "locos" / String / "speed")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticated(bridge.clone()))
+        .and_then(|address, body: SetVelocity, bridge, login| {
+            command(
+                bridge,
+                login,
+                address,
+                WiMessageType::Velocity(body.velocity),
+            )
+        });
+
+    let direction = warp::path!("api" / "locos" / String / "direction")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticated(bridge.clone()))
+        .and_then(|address, body: SetDirection, bridge, login| {
+            command(
+                bridge,
+                login,
+                address,
+                WiMessageType::Direction(body.direction),
+            )
+        });
+
+    // JMRI toggles a function on every press
+    let function = warp::path!("api" / "locos" / String / "functions" / String)
+        .and(warp::post())
+        .and(authenticated(bridge.clone()))
+        .and_then(|address, function: String, bridge, login| async move {
+            match function.parse::<Function>() {
+                Ok(function) => {
+                    let message_type = WiMessageType::FunctionPressed(function);
+                    command(bridge, login, address, message_type).await
+                }
+                Err(e) => Ok(to_response(Err(CommandError::Parse(e)))),
+            }
+        });
+
+    let clock = warp::path!("api" / "clock")
+        .and(warp::get())
+        .and(authenticated(bridge.clone()))
+        .and_then(|bridge: Arc<Bridge>, login: LoggedIn| async move {
+            if let Err(e) = login {
+                return Ok::<_, Infallible>(to_response(Err(e)));
+            }
+            let (time, rate) = *bridge.time.read().await;
+            Ok(json(&Clock { time, rate }).into_response())
+        });
+
+    let roster = warp::path!("api" / "roster")
+        .and(warp::get())
+        .and(authenticated(bridge.clone()))
+        .and_then(|bridge: Arc<Bridge>, login: LoggedIn| async move {
+            if let Err(e) = login {
+                return Ok::<_, Infallible>(to_response(Err(e)));
+            }
+            Ok(json(&*bridge.roster.read().await).into_response())
+        });
+
+    let turnouts = warp::path!("api" / "turnouts")
+        .and(warp::get())
+        .and(authenticated(bridge.clone()))
+        .and_then(|bridge: Arc<Bridge>, login: LoggedIn| async move {
+            if let Err(e) = login {
+                return Ok::<_, Infallible>(to_response(Err(e)));
+            }
+            Ok(json(&*bridge.turnouts.read().await).into_response())
+        });
+
+    let set_turnout = warp::path!("api" / "turnouts" / String)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticated(bridge.clone()))
+        .and_then(set_turnout);
+
+    let estop = warp::path!("api" / "estop")
+        .and(warp::post())
+        .and(authenticated(bridge))
+        .and_then(emergency_stop);
+
+    locos
+        .or(acquire)
+        .or(release)
+        .or(speed)
+        .or(direction)
+        .or(function)
+        .or(clock)
+        .or(roster)
+        .or(turnouts)
+        .or(set_turnout)
+        .or(estop)
+}
+
+/// Who a request is logged in as, or why it couldn't log in.
+type LoggedIn = Result<Login, CommandError>;
+
+/// Logs a request in with the PIN or token from [credentials], without starting a client.
+fn authenticated(
+    bridge: Arc<Bridge>,
+) -> impl Filter<Extract = (Arc<Bridge>, LoggedIn), Error = Rejection> + Clone {
+    with_bridge(bridge)
+        .and(credentials())
+        .and_then(
+            |bridge: Arc<Bridge>, credentials: Option<String>| async move {
+                let login = bridge.config.auth.login(credentials.as_deref());
+                Ok::<_, Infallible>((bridge, login))
+            },
+        )
+        .untuple_one()
+}
+
+/// The PIN or token from an `Authorization: Bearer <token>` header.
+pub(crate) fn credentials() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(|header: Option<String>| {
+        header.map(|header| match header.strip_prefix("Bearer ") {
+            Some(token) => token.to_string(),
+            None => header,
+        })
+    })
+}
+
+/// Every address held by any client, with the last state JMRI reported for it.
+async fn list_locos(bridge: Arc<Bridge>, login: LoggedIn) -> Result<Response, Infallible> {
+    if let Err(e) = login {
+        return Ok(to_response(Err(e)));
+    }
+    let addresses: BTreeSet<LocoAddress> = bridge
+        .clients
+        .read()
+        .await
+        .values()
+        .flat_map(|client| client.addresses.keys().copied())
+        .collect();
+    let states = bridge.locos.read().await;
+    let locos: Vec<Loco> = addresses
+        .into_iter()
+        .map(|address| Loco {
+            address,
+            state: states.get(&address).cloned().unwrap_or_default(),
+        })
+        .collect();
+    Ok(json(&locos).into_response())
+}
+
+async fn command(
+    bridge: Arc<Bridge>,
+    login: LoggedIn,
+    address: String,
+    message_type: WiMessageType,
+) -> Result<Response, Infallible> {
+    let (login, address) = match (login, parse_address(&bridge, &address)) {
+        (Ok(login), Ok(address)) => (login, address),
+        (Err(e), _) | (_, Err(e)) => return Ok(to_response(Err(e))),
+    };
+    let id = match message_type {
+        WiMessageType::AddAddress => Some(bridge.open_rest_client(login).await),
+        _ => bridge.rest_client(&login.user).await,
+    };
+    // Without a client the user holds nothing to command
+    let Some(id) = id else {
+        return Ok(to_response(Err(CommandError::NotOwner(address))));
+    };
+    let result = process_message(&bridge, id, WiMessage::new(address, message_type)).await;
+    if message_type.is_address() {
+        bridge.close_idle_rest_client(id).await;
+    }
+    Ok(to_response(result))
+}
+
+/// An address from a path, see the module docs.
+pub(crate) fn parse_address(bridge: &Bridge, address: &str) -> Result<LocoAddress, CommandError> {
+    LocoAddress::parse_with_default(address, bridge.config.short_address_limit)
+        .map_err(CommandError::Parse)
+}
+
+/// Stops every loco on the layout, for logins with the `power` permission.
+async fn emergency_stop(bridge: Arc<Bridge>, login: LoggedIn) -> Result<Response, Infallible> {
+    let result = match login {
+        Ok(login) if login.permissions.view_only => Err(CommandError::ViewOnly),
+        Ok(login) if !login.permissions.power => Err(CommandError::PowerNotPermitted),
+        Ok(_) if bridge.jmri_connected.load(Ordering::Relaxed) => {
+            let throttles = bridge.throttles().await;
+            bridge.velocity_coalescer.emergency_stop(throttles).await
+        }
+        Ok(_) => Err(CommandError::JmriDisconnected),
+        Err(e) => Err(e),
+    };
+    Ok(to_response(result))
+}
+
+/// Closes or throws a turnout by its system name, for logins with the `turnouts` permission.
+/// The new state shows up in `/api/turnouts` once JMRI reports it.
+async fn set_turnout(
+    system_name: String,
+    body: SetTurnout,
+    bridge: Arc<Bridge>,
+    login: LoggedIn,
+) -> Result<Response, Infallible> {
+    let result = match login {
+        Ok(login) if login.permissions.view_only => Err(CommandError::ViewOnly),
+        Ok(login) if !login.permissions.turnouts => Err(CommandError::TurnoutsNotPermitted),
+        Ok(_) if !bridge.jmri_connected.load(Ordering::Relaxed) => {
+            Err(CommandError::JmriDisconnected)
+        }
+        Ok(_) => match format_turnout_command(&system_name, body.state) {
+            Some(line) => bridge
+                .to_jmri
+                .tx
+                .send(line)
+                .await
+                .map_err(|_| CommandError::JmriDisconnected),
+            None => Err(CommandError::Parse(format!(
+                "Turnouts can only be closed or thrown, not {:?}",
+                body.state
+            ))),
+        },
+        Err(e) => Err(e),
+    };
+    Ok(to_response(result))
+}
+
+pub(crate) fn to_response(result: Result<(), CommandError>) -> Response {
+    let error = match result {
+        Ok(()) => return StatusCode::NO_CONTENT.into_response(),
+        Err(error) => error,
+    };
+    let status = match error {
+        CommandError::Parse(_) => StatusCode::BAD_REQUEST,
+        CommandError::Unauthorized => StatusCode::UNAUTHORIZED,
+        CommandError::NotOwner(_)
+        | CommandError::ViewOnly
+        | CommandError::AddressNotPermitted(_)
+        | CommandError::AdminOnly
+        | CommandError::PowerNotPermitted
+        | CommandError::TurnoutsNotPermitted => StatusCode::FORBIDDEN,
+        CommandError::AddressInUse(_) => StatusCode::CONFLICT,
+        CommandError::JmriDisconnected | CommandError::NoFreeThrottle => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        CommandError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+    };
+    let body = ErrorBody {
+        error: error.to_string(),
+    };
+    with_status(json(&body), status).into_response()
+}
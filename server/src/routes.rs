@@ -0,0 +1,60 @@
+use crate::admin;
+use crate::bridge::Bridge;
+use crate::health::Readiness;
+use crate::rest;
+use crate::ws::{handle_connection, ConnectParams};
+use std::convert::Infallible;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+pub fn routes(
+    bridge: Arc<Bridge>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Liveness, `/health` is kept for supervisors that already poll it
+    let health = warp::path!("health")
+        .or(warp::path!("health" / "live"))
+        .unify()
+        .and(warp::get())
+        .map(|| warp::reply::with_status("Healthy", StatusCode::OK));
+
+    let ready = warp::path!("health" / "ready")
+        .and(warp::get())
+        .and(with_bridge(bridge.clone()))
+        .then(|bridge: Arc<Bridge>| async move {
+            let readiness = Readiness::of(&bridge).await;
+            let status = match readiness.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            warp::reply::with_status(warp::reply::json(&readiness), status)
+        });
+
+    let ws = warp::path("ws")
+        .and(warp::ws())
+        .and(warp::query::<ConnectParams>())
+        .and(with_bridge(bridge.clone()))
+        .map(
+            |ws: warp::ws::Ws, params: ConnectParams, bridge: Arc<Bridge>| {
+                ws.on_upgrade(move |socket| handle_connection(bridge, socket, params))
+            },
+        );
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_bridge(bridge.clone()))
+        .then(|bridge: Arc<Bridge>| async move { bridge.metrics.render(&bridge).await });
+
+    health
+        .or(ready)
+        .or(metrics)
+        .or(ws)
+        .or(rest::api(bridge.clone()))
+        .or(admin::routes(bridge))
+}
+
+pub(crate) fn with_bridge(
+    bridge: Arc<Bridge>,
+) -> impl Filter<Extract = (Arc<Bridge>,), Error = Infallible> + Clone {
+    warp::any().map(move || bridge.clone())
+}
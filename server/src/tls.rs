@@ -0,0 +1,116 @@
+//! Optional TLS for the HTTP/WebSocket listener, from a configured certificate and key or a
+//! generated self-signed certificate. A self-signed certificate is written to the configured
+//! paths when they're set and don't exist yet, so browsers only have to trust it once.
+
+use crate::bridge::Config;
+use crate::discovery::host_name;
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A PEM encoded certificate chain and private key.
+pub struct Identity {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Identity {
+    /// The identity to serve with, `None` when TLS is disabled.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => (Some(cert), Some(key)),
+            (None, None) => (None, None),
+            _ => return Err("TLS_CERT and TLS_KEY have to be set together".into()),
+        };
+
+        if let (Some(cert), Some(key)) = (cert_path, key_path) {
+            if !config.tls_self_signed || (cert.exists() && key.exists()) {
+                info!("Serving TLS with the certificate in {cert:?}");
+                return Ok(Some(Self {
+                    cert: fs::read(cert)?,
+                    key: fs::read(key)?,
+                }));
+            }
+        }
+        if !config.tls_self_signed {
+            return Ok(None);
+        }
+
+        let names = vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+            host_name(&config.throttle_name)
+                .trim_end_matches('.')
+                .to_string(),
+        ];
+        let identity = Self::self_signed(names)?;
+        if let (Some(cert), Some(key)) = (cert_path, key_path) {
+            fs::write(cert, &identity.cert)?;
+            write_private(key, &identity.key)?;
+            info!("Wrote a self-signed certificate to {cert:?}");
+        } else {
+            info!("Serving TLS with a self-signed certificate, it changes on every restart");
+        }
+        Ok(Some(identity))
+    }
+
+    pub fn self_signed(names: Vec<String>) -> Result<Self, rcgen::Error> {
+        let cert = rcgen::generate_simple_self_signed(names)?;
+        Ok(Self {
+            cert: cert.serialize_pem()?.into_bytes(),
+            key: cert.serialize_private_key_pem().into_bytes(),
+        })
+    }
+}
+
+/// Writes a file only its owner can read, for the private key.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn self_signed_certificate_is_kept() {
+        let dir = std::env::temp_dir();
+        let config = Config {
+            tls_cert: Some(dir.join(format!("cert-{}.pem", Uuid::new_v4()))),
+            tls_key: Some(dir.join(format!("key-{}.pem", Uuid::new_v4()))),
+            tls_self_signed: true,
+            ..Config::default()
+        };
+
+        let generated = Identity::from_config(&config).unwrap().unwrap();
+        assert!(generated.cert.starts_with(b"-----BEGIN CERTIFICATE-----"));
+        let reloaded = Identity::from_config(&config).unwrap().unwrap();
+        assert_eq!(generated.cert, reloaded.cert);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = fs::metadata(config.tls_key.as_ref().unwrap()).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::remove_file(config.tls_cert.unwrap()).unwrap();
+        fs::remove_file(config.tls_key.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn disabled_without_configuration() {
+        assert!(Identity::from_config(&Config::default()).unwrap().is_none());
+        let config = Config {
+            tls_key: Some("key.pem".into()),
+            ..Config::default()
+        };
+        assert!(Identity::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Lets native WiThrottle apps (Engine Driver, WiThrottle) connect to the bridge over TCP.
+//! Their commands go through the same checks as WebSocket clients and share the single JMRI
+//! connection, and they receive the same updates, formatted back into WiThrottle lines.
+
+use crate::bridge::Bridge;
+use crate::client::{Client, SharedMessage, CLIENT_CHANNEL_CAPACITY};
+use crate::jmri::handle_command;
+use futures::{SinkExt, StreamExt};
+use jmri_throttle_rs::layout::{format_roster, format_turnouts};
+use jmri_throttle_rs::message::{WiMessage, WiMessageType};
+use jmri_throttle_rs::protocol::ServerMessage;
+use jmri_throttle_rs::server_info::Notification;
+use log::{debug, error, info};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::{Framed, LinesCodec};
+use uuid::Uuid;
+
+/// Heartbeat interval in seconds advertised to native throttles.
+const HEARTBEAT_SECONDS: u32 = 10;
+
+pub async fn serve(bridge: Arc<Bridge>, listener: TcpListener) -> io::Result<()> {
+    info!(
+        "Listening for WiThrottle clients on {}",
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("WiThrottle connection from {peer}");
+        tokio::spawn(handle_connection(bridge.clone(), stream));
+    }
+}
+
+/// Formats a server message as the WiThrottle line a native throttle expects, if it has one.
+fn to_line(message: &ServerMessage) -> Option<String> {
+    match message {
+        // Reported to throttles like JMRI does, `X` only being a command
+        ServerMessage::Update(message) if message.message_type == WiMessageType::EmergencyStop => {
+            Some(format!("M{}A{}<;>V-1", message.throttle, message.address))
+        }
+        ServerMessage::Update(message) => Some(message.to_string()),
+        ServerMessage::Welcome { .. }
+        | ServerMessage::Ack { .. }
+        | ServerMessage::ServerInfo(_) => None,
+        // Shown as an alert by WiThrottle apps
+        ServerMessage::Error { error, .. } => Some(format!("HM{error}")),
+        ServerMessage::Notification(Notification::Alert(alert)) => Some(format!("HM{alert}")),
+        ServerMessage::Notification(Notification::Info(info)) => Some(format!("Hm{info}")),
+    }
+}
+
+async fn handle_connection(bridge: Arc<Bridge>, stream: TcpStream) {
+    let id = Uuid::new_v4();
+    debug!("New WiThrottle id: {id}");
+
+    let (mut tcp_tx, mut tcp_rx) = Framed::new(stream, LinesCodec::new()).split::<String>();
+
+    // The WiThrottle protocol has no way to send a PIN or token, so native throttles are only
+    // let in while authentication is disabled
+    let login = match bridge.config.auth.login(None) {
+        Ok(login) => login,
+        Err(error) => {
+            info!("Refusing WiThrottle client '{id}', authentication is required");
+            let _ = tcp_tx.send(format!("HM{error}")).await;
+            return;
+        }
+    };
+
+    bridge.metrics.client_connections.inc();
+    let (to_client_tx, mut to_client_rx) = mpsc::channel::<SharedMessage>(CLIENT_CHANNEL_CAPACITY);
+
+    let client = Client::new(id, to_client_tx, bridge.config.client_rate_limit).with_login(login);
+    let disconnect = client.disconnect.clone();
+    bridge.clients.write().await.insert(id, client);
+
+    let mut client_receive_handle = {
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            while let Some(line) = tcp_rx.next().await {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("WiThrottle error(uid={id}, e={e})");
+                        break;
+                    }
+                };
+                let line = line.trim();
+                match line.chars().next() {
+                    Some('M') => match WiMessage::from_str(line) {
+                        Ok(message) => handle_command(&bridge, id, None, message).await,
+                        Err(e) => {
+                            debug!("Ignoring WiThrottle line(uid={id}, e={e})");
+                            bridge
+                                .metrics
+                                .parse_errors
+                                .with_label_values(&["client"])
+                                .inc();
+                        }
+                    },
+                    Some('N') => {
+                        info!("WiThrottle client '{id}' is named {}", &line[1..]);
+                        if let Some(client) = bridge.clients.write().await.get_mut(&id) {
+                            client.name = line[1..].to_string();
+                        }
+                    }
+                    // Quit
+                    Some('Q') => return,
+                    // Heartbeats, device IDs and anything else the bridge doesn't handle
+                    _ => debug!("Ignoring WiThrottle line(uid={id}, line={line})"),
+                }
+            }
+        })
+    };
+
+    // What JMRI sends a new throttle, from what it sent the bridge, so it can pick a loco
+    let handshake = [
+        "VN2.0".to_string(),
+        format_roster(&bridge.roster.read().await),
+        format_turnouts(&bridge.turnouts.read().await),
+        format!("*{HEARTBEAT_SECONDS}"),
+        {
+            let (time, rate) = *bridge.time.read().await;
+            WiMessage::time(time, rate).to_string()
+        },
+    ];
+    let client_send_handle = tokio::spawn(async move {
+        for line in handshake {
+            if let Err(e) = tcp_tx.send(line).await {
+                error!("Error sending to WiThrottle client '{id}': {e}");
+                return;
+            }
+        }
+
+        while let Some(message) = to_client_rx.recv().await {
+            let Some(line) = to_line(message.message()) else {
+                continue;
+            };
+            if let Err(e) = tcp_tx.send(line).await {
+                error!("Error sending to WiThrottle client '{id}': {e}");
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = &mut client_receive_handle => {}
+        _ = disconnect.notified() => client_receive_handle.abort(),
+    }
+    client_send_handle.abort();
+
+    bridge.remove_client(id).await;
+}
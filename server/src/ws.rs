@@ -1,77 +1,152 @@
-use crate::client::{Client, CLIENTS};
+use crate::bridge::Bridge;
+use crate::client::{Client, SharedMessage, CLIENT_CHANNEL_CAPACITY};
 use crate::jmri::handle_message;
-use crate::{TIME, TO_JMRI};
 
+use futures::stream::SplitStream;
 use futures::{SinkExt, StreamExt};
-use jmri_throttle_rs::message::{WiMessage, WiMessageType};
+use jmri_throttle_rs::message::WiMessage;
+use jmri_throttle_rs::protocol::{CommandError, Encoding, Hello, Payload, ServerMessage};
 use log::Level::Debug;
 use log::{debug, error, log_enabled};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::time::timeout;
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
-pub async fn handle_connection(ws: WebSocket) {
+/// How long a client has to send its [Hello] after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ConnectParams {
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+fn to_ws_message(payload: &Payload) -> Message {
+    match payload {
+        Payload::Text(text) => Message::text(text.as_str()),
+        Payload::Binary(bytes) => Message::binary(bytes.as_slice()),
+    }
+}
+
+/// Waits for the [Hello] every connection starts with, or `None` if the client goes away first.
+async fn receive_hello(
+    ws_rx: &mut SplitStream<WebSocket>,
+    encoding: Encoding,
+) -> Option<Result<Hello, String>> {
+    while let Some(Ok(message)) = ws_rx.next().await {
+        if message.is_close() {
+            return None;
+        }
+        if message.is_text() || message.is_binary() {
+            return Some(encoding.decode(message.as_bytes()));
+        }
+    }
+    None
+}
+
+pub async fn handle_connection(bridge: Arc<Bridge>, ws: WebSocket, params: ConnectParams) {
     let id = Uuid::new_v4();
-    debug!("New id: {id}");
+    let encoding = params.encoding;
+    debug!("New id: {id}, encoding: {encoding}");
 
     // WebSocket streams
     let (mut ws_tx, mut ws_rx) = ws.split();
 
+    let hello = match timeout(HELLO_TIMEOUT, receive_hello(&mut ws_rx, encoding)).await {
+        Ok(Some(hello)) => hello,
+        Ok(None) | Err(_) => {
+            debug!("No hello from '{id}'");
+            return;
+        }
+    };
+    let login = hello
+        .map_err(CommandError::Parse)
+        .and_then(|hello| bridge.config.auth.login(hello.token.as_deref()));
+    let login = match login {
+        Ok(login) => login,
+        Err(error) => {
+            debug!("Login failed for '{id}'");
+            // Sent before closing so the client can tell why
+            let error = ServerMessage::Error {
+                request_id: None,
+                error,
+            };
+            let _ = ws_tx.send(to_ws_message(&encoding.encode(&error))).await;
+            let _ = ws_tx.close().await;
+            return;
+        }
+    };
+    bridge.metrics.client_connections.inc();
+    let welcome = ServerMessage::Welcome {
+        user: login.user.clone(),
+        permissions: login.permissions.clone(),
+    };
+
     // Client channels
-    let (to_client_tx, to_client_rx) = mpsc::unbounded_channel::<String>();
-    let mut to_client_rx = UnboundedReceiverStream::new(to_client_rx);
+    let (to_client_tx, mut to_client_rx) = mpsc::channel::<SharedMessage>(CLIENT_CHANNEL_CAPACITY);
 
-    CLIENTS
-        .write()
-        .await
-        .insert(id, Client::new(id, to_client_tx));
+    let client = Client::new(id, to_client_tx, bridge.config.client_rate_limit).with_login(login);
+    let disconnect = client.disconnect.clone();
+    bridge.clients.write().await.insert(id, client);
 
     if log_enabled!(Debug) {
-        let clients = CLIENTS.read().await;
+        let clients = bridge.clients.read().await;
         debug!("Number of clients: {}", clients.len());
         debug!("Current clients: {:?}", clients.keys());
     }
 
-    let client_receive_handle = tokio::spawn(async move {
-        while let Some(result) = ws_rx.next().await {
-            let message = match result {
-                Ok(message) => message,
-                Err(e) => {
-                    error!("Websocket error(uid={id}, e={e})");
-                    break;
+    let mut client_receive_handle = {
+        let bridge = bridge.clone();
+        tokio::spawn(async move {
+            while let Some(result) = ws_rx.next().await {
+                let message = match result {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("Websocket error(uid={id}, e={e})");
+                        break;
+                    }
+                };
+                // If we were sent a close, return to start cleanup at the end of handle_connection
+                if message.is_close() {
+                    return;
                 }
-            };
-            // If we were sent a close, return to start cleanup at the end of handle_connection
-            if message.is_close() {
-                return;
+                handle_message(&bridge, id, encoding, message).await;
             }
-            handle_message(id, message).await;
-        }
-    });
+        })
+    };
 
+    let (time, rate) = *bridge.time.read().await;
+    let server_info = bridge.server_info.read().await.clone();
     let client_send_handle = tokio::spawn(async move {
-        let time_message =
-            serde_json::to_string(&WiMessage::new(0, WiMessageType::Time(*TIME.read().await)))
-                .unwrap();
-        ws_tx.send(Message::text(time_message)).await.unwrap();
+        let time_message = WiMessage::time(time, rate);
+        let greeting = [
+            welcome,
+            ServerMessage::ServerInfo(server_info),
+            ServerMessage::Update(time_message),
+        ];
+        for message in greeting {
+            if let Err(e) = ws_tx.send(to_ws_message(&encoding.encode(&message))).await {
+                error!("Error sending to client '{id}': {e}");
+                return;
+            }
+        }
 
-        while let Some(message) = to_client_rx.next().await {
-            if let Err(e) = ws_tx.send(Message::text(message)).await {
+        while let Some(message) = to_client_rx.recv().await {
+            if let Err(e) = ws_tx.send(to_ws_message(message.encode(encoding))).await {
                 error!("Error sending to client '{id}': {e}");
             };
         }
     });
 
-    client_receive_handle.await.ok();
-    drop(client_send_handle);
-
-    if let Some(client) = CLIENTS.write().await.remove(&id) {
-        let mut messages: Vec<String> = Vec::new();
-        for address in client.addresses {
-            messages.push(WiMessage::new(address, WiMessageType::RemoveAddress).to_string())
-        }
-        TO_JMRI.tx.write().await.send(messages.join("\n")).unwrap();
+    tokio::select! {
+        _ = &mut client_receive_handle => {}
+        _ = disconnect.notified() => client_receive_handle.abort(),
     }
-    debug!("Removed client '{id}'");
+    client_send_handle.abort();
+
+    bridge.remove_client(id).await;
 }
@@ -0,0 +1,114 @@
+mod common;
+
+use common::{connect, expect, is_update, next_line, send, start_with, TIMEOUT};
+use futures::StreamExt;
+use jmri_throttle_rs::message::{LocoAddress, Velocity, WiMessage, WiMessageType};
+use server::auth::Auth;
+use server::routes::routes;
+use server::Config;
+use tokio::time::timeout;
+use warp::http::StatusCode;
+
+fn config() -> Config {
+    let users = serde_json::from_str(
+        r#"{"admin": {"token": "admin", "admin": true}, "driver": {"token": "driver"}}"#,
+    )
+    .unwrap();
+    Config {
+        auth: Auth {
+            pin: None,
+            users,
+            required: true,
+        },
+        ..Config::default()
+    }
+}
+
+/// A request to the admin API, logged in as the admin.
+fn admin_request() -> warp::test::RequestBuilder {
+    warp::test::request().header("authorization", "Bearer admin")
+}
+
+#[tokio::test]
+async fn release_and_disconnect_sessions() {
+    let (mock, bridge, addr) = start_with(config()).await;
+    let mut ws = connect(addr, Some("driver")).await.unwrap();
+    next_line(&mock).await;
+    next_line(&mock).await;
+    let routes = routes(bridge);
+
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Velocity(Velocity::STOP))
+    })
+    .await;
+
+    let response = admin_request()
+        .path("/api/admin/sessions")
+        .reply(&routes)
+        .await;
+    let sessions: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(sessions[0]["user"], "driver");
+    assert_eq!(sessions[0]["locos"][0]["address"]["number"], 3);
+    let id = sessions[0]["id"].as_str().unwrap();
+
+    let response = admin_request()
+        .method("DELETE")
+        .path(&format!("/api/admin/sessions/{id}/locos/3"))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(next_line(&mock).await, "MT-S3<;>S3");
+    expect(&mut ws, |m| is_update(m, WiMessageType::RemoveAddress)).await;
+
+    let response = admin_request()
+        .method("DELETE")
+        .path(&format!("/api/admin/sessions/{id}"))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let closed = timeout(TIMEOUT, async {
+        while let Some(Ok(message)) = ws.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+    });
+    closed.await.unwrap();
+}
+
+#[tokio::test]
+async fn only_admins_see_sessions() {
+    let (_mock, bridge, _addr) = start_with(config()).await;
+    let routes = routes(bridge);
+
+    let logins = [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some("driver"), StatusCode::FORBIDDEN),
+        (Some("admin"), StatusCode::OK),
+    ];
+    for (token, status) in logins {
+        let mut request = warp::test::request().path("/api/admin/sessions");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        let response = request.reply(&routes).await;
+        assert_eq!(response.status(), status);
+    }
+}
+
+#[tokio::test]
+async fn guests_are_not_admins() {
+    let (_mock, bridge, _addr) = start_with(Config::default()).await;
+    let response = warp::test::request()
+        .path("/api/admin/sessions")
+        .reply(&routes(bridge))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
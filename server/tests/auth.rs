@@ -0,0 +1,206 @@
+mod common;
+
+use common::{connect, expect, next_line, send, start_with, start_withrottle, TIMEOUT};
+use futures::StreamExt;
+use jmri_throttle_rs::message::{LocoAddress, WiMessage, WiMessageType};
+use jmri_throttle_rs::protocol::{CommandError, ServerMessage};
+use server::auth::Auth;
+use server::routes::routes;
+use server::Config;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, LinesCodec};
+use warp::http::StatusCode;
+
+fn auth() -> Auth {
+    let users = serde_json::from_str(
+        r#"{"visitor": {"token": "v1s1tor", "view_only": true},
+            "yardmaster": {"token": "yard", "addresses": [["Short", 1, 99]], "steal": false,
+                           "power": false, "turnouts": false}}"#,
+    )
+    .unwrap();
+    Auth {
+        pin: Some("1234".to_string()),
+        users,
+        required: true,
+    }
+}
+
+fn is_error(message: &ServerMessage, expected: CommandError) -> bool {
+    matches!(message, ServerMessage::Error { error, .. } if *error == expected)
+}
+
+fn config() -> Config {
+    Config {
+        auth: auth(),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn websocket_logins() {
+    let (mock, _bridge, addr) = start_with(config()).await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    let mut ws = connect(addr, Some("4321")).await.unwrap();
+    expect(&mut ws, |m| is_error(m, CommandError::Unauthorized)).await;
+    // Closed by the server right after
+    assert!(timeout(TIMEOUT, ws.next())
+        .await
+        .unwrap()
+        .is_none_or(|m| m.unwrap().is_close()));
+
+    let mut club = connect(addr, Some("1234")).await.unwrap();
+    expect(
+        &mut club,
+        |m| matches!(m, ServerMessage::Welcome { user, .. } if user == "club"),
+    )
+    .await;
+    send(
+        &mut club,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+
+    let mut visitor = connect(addr, Some("v1s1tor")).await.unwrap();
+    expect(
+        &mut visitor,
+        |m| matches!(m, ServerMessage::Welcome { permissions, .. } if permissions.view_only),
+    )
+    .await;
+    send(
+        &mut visitor,
+        1,
+        WiMessage::new(LocoAddress::short(5).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    expect(&mut visitor, |m| is_error(m, CommandError::ViewOnly)).await;
+
+    // The club already holds 3, and the yardmaster may neither steal nor leave the yard
+    let mut yardmaster = connect(addr, Some("yard")).await.unwrap();
+    send(
+        &mut yardmaster,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    expect(&mut yardmaster, |m| {
+        is_error(
+            m,
+            CommandError::AddressInUse(LocoAddress::short(3).unwrap()),
+        )
+    })
+    .await;
+    send(
+        &mut yardmaster,
+        2,
+        WiMessage::new(LocoAddress::short(100).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    expect(&mut yardmaster, |m| {
+        is_error(
+            m,
+            CommandError::AddressNotPermitted(LocoAddress::short(100).unwrap()),
+        )
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rest_requests_need_a_bearer_token() {
+    let (_mock, bridge, _addr) = start_with(config()).await;
+    let routes = routes(bridge);
+
+    let response = warp::test::request()
+        .path("/api/roster")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = warp::test::request()
+        .path("/api/roster")
+        .header("authorization", "Bearer v1s1tor")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/locos/3")
+        .header("authorization", "Bearer v1s1tor")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn layout_wide_commands_need_their_permission() {
+    let (mock, bridge, _addr) = start_with(config()).await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+    let routes = routes(bridge);
+
+    for (token, expected) in [
+        ("v1s1tor", Some(CommandError::ViewOnly)),
+        ("yard", Some(CommandError::PowerNotPermitted)),
+        ("1234", None),
+    ] {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/estop")
+            .header("authorization", format!("Bearer {token}"))
+            .reply(&routes)
+            .await;
+        assert_error(response, expected);
+    }
+    assert_eq!(next_line(&mock).await, "MTA*<;>X");
+
+    for (token, expected) in [
+        ("v1s1tor", Some(CommandError::ViewOnly)),
+        ("yard", Some(CommandError::TurnoutsNotPermitted)),
+        ("1234", None),
+    ] {
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/turnouts/LT1")
+            .header("authorization", format!("Bearer {token}"))
+            .json(&serde_json::json!({"state": "Thrown"}))
+            .reply(&routes)
+            .await;
+        assert_error(response, expected);
+    }
+    assert_eq!(next_line(&mock).await, "PTATLT1");
+}
+
+/// Checks a REST response failed with `expected`, or succeeded without one.
+fn assert_error(
+    response: warp::http::Response<warp::hyper::body::Bytes>,
+    expected: Option<CommandError>,
+) {
+    match expected {
+        Some(error) => {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["error"], error.to_string());
+        }
+        None => assert_eq!(response.status(), StatusCode::NO_CONTENT),
+    }
+}
+
+#[tokio::test]
+async fn native_throttles_are_refused() {
+    let (_mock, bridge, _addr) = start_with(config()).await;
+    let addr = start_withrottle(&bridge).await;
+    let mut throttle = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+
+    let line = timeout(TIMEOUT, throttle.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(line, "HMWrong PIN or token");
+    assert!(timeout(TIMEOUT, throttle.next()).await.unwrap().is_none());
+}
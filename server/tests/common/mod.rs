@@ -0,0 +1,111 @@
+#![allow(dead_code)]
+use futures::{SinkExt, StreamExt};
+use jmri_throttle_rs::message::{WiMessage, WiMessageType};
+use jmri_throttle_rs::protocol::{ClientMessage, Hello, ServerMessage};
+use server::jmri::jmri_conn;
+use server::mock_jmri::MockJmri;
+use server::routes::routes;
+use server::{withrottle, Bridge, Config};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn start() -> (MockJmri, Arc<Bridge>, WebSocket) {
+    let (mock, bridge, addr) = start_with(Config::default()).await;
+    let ws = connect(addr, None).await.unwrap();
+    // Logged in once the bridge has read the hello
+    wait_until(|| bridge.clients.try_read().is_ok_and(|c| !c.is_empty())).await;
+    (mock, bridge, ws)
+}
+
+/// Starts a bridge on `config` against a mock JMRI, returning the bridge's HTTP address.
+pub async fn start_with(config: Config) -> (MockJmri, Arc<Bridge>, SocketAddr) {
+    let mock = MockJmri::start("127.0.0.1:0").await.unwrap();
+    let bridge = Bridge::new(Config {
+        jmri_server: Some(mock.addr().to_string()),
+        advertise: false,
+        velocity_window: Duration::ZERO,
+        ..config
+    });
+
+    tokio::spawn(jmri_conn(bridge.clone()));
+    wait_until(|| bridge.jmri_connected.load(Ordering::Relaxed)).await;
+
+    let (addr, server) = warp::serve(routes(bridge.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    (mock, bridge, addr)
+}
+
+/// Polls until `condition` holds, failing after [TIMEOUT].
+pub async fn wait_until(condition: impl Fn() -> bool) {
+    timeout(TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap()
+}
+
+/// Connects and logs in with `token`, if any.
+pub async fn connect(addr: SocketAddr, token: Option<&str>) -> Result<WebSocket, WsError> {
+    let (mut ws, _) = connect_async(format!("ws://{addr}/ws")).await?;
+    let hello = Hello {
+        token: token.map(str::to_string),
+    };
+    ws.send(Message::text(serde_json::to_string(&hello).unwrap()))
+        .await?;
+    Ok(ws)
+}
+
+pub async fn next_line(mock: &MockJmri) -> String {
+    timeout(TIMEOUT, mock.next_line()).await.unwrap().unwrap()
+}
+
+pub async fn send(ws: &mut WebSocket, request_id: u32, message: WiMessage) {
+    let message = ClientMessage::new(Some(request_id), message);
+    let message = serde_json::to_string(&message).unwrap();
+    ws.send(Message::text(message)).await.unwrap();
+}
+
+/// Waits for the first server message matching `predicate`, skipping everything else.
+pub async fn expect(
+    ws: &mut WebSocket,
+    predicate: impl Fn(&ServerMessage) -> bool,
+) -> ServerMessage {
+    timeout(TIMEOUT, async {
+        while let Some(Ok(message)) = ws.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let message: ServerMessage = serde_json::from_str(&text).unwrap();
+            if predicate(&message) {
+                return message;
+            }
+        }
+        panic!("WebSocket closed");
+    })
+    .await
+    .unwrap()
+}
+
+pub fn is_update(message: &ServerMessage, message_type: WiMessageType) -> bool {
+    matches!(message, ServerMessage::Update(m) if m.message_type == message_type)
+}
+
+pub async fn start_withrottle(bridge: &Arc<Bridge>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(withrottle::serve(bridge.clone(), listener));
+    addr
+}
@@ -0,0 +1,315 @@
+mod common;
+
+use common::{connect, expect, is_update, next_line, send, start, start_with, wait_until};
+use jmri_throttle_rs::message::{
+    ClockRate, Direction, Function, LocoAddress, Target, ThrottleId, Velocity, WiMessage,
+    WiMessageType,
+};
+use jmri_throttle_rs::protocol::ServerMessage;
+use jmri_throttle_rs::server_info::Notification;
+use server::routes::routes;
+use server::Config;
+use std::sync::atomic::Ordering;
+use warp::http::StatusCode;
+
+#[tokio::test]
+async fn handshake_names_the_throttle() {
+    let (mock, _bridge, _ws) = start().await;
+    assert!(next_line(&mock).await.starts_with("HU"));
+    assert_eq!(next_line(&mock).await, "NTestThrottleRs");
+}
+
+#[tokio::test]
+async fn acquire_drive_and_release() {
+    let (mock, _bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+    expect(&mut ws, |m| {
+        matches!(m, ServerMessage::Ack { request_id: 1 })
+    })
+    .await;
+
+    send(
+        &mut ws,
+        2,
+        WiMessage::new(
+            LocoAddress::short(3).unwrap(),
+            WiMessageType::Velocity(Velocity::try_from(40).unwrap()),
+        ),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MTAS3<;>V40");
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Velocity(Velocity::try_from(40).unwrap()))
+    })
+    .await;
+
+    send(
+        &mut ws,
+        3,
+        WiMessage::new(
+            LocoAddress::short(3).unwrap(),
+            WiMessageType::FunctionPressed(Function::try_from(2).unwrap()),
+        ),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MTAS3<;>F12");
+    expect(&mut ws, |m| {
+        is_update(
+            m,
+            WiMessageType::FunctionPressed(Function::try_from(2).unwrap()),
+        )
+    })
+    .await;
+
+    send(
+        &mut ws,
+        4,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::RemoveAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT-S3<;>S3");
+    expect(&mut ws, |m| is_update(m, WiMessageType::RemoveAddress)).await;
+}
+
+#[tokio::test]
+async fn wildcard_updates_reach_every_holder() {
+    let (mock, bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Velocity(Velocity::STOP))
+    })
+    .await;
+
+    mock.send("MTA*<;>R0");
+    expect(&mut ws, |m| {
+        matches!(m, ServerMessage::Update(message)
+            if message.address == Target::All
+                && message.message_type == WiMessageType::Direction(Direction::Reverse))
+    })
+    .await;
+    let locos = bridge.locos.read().await;
+    assert_eq!(
+        locos[&LocoAddress::short(3).unwrap()].direction,
+        Direction::Reverse
+    );
+}
+
+#[tokio::test]
+async fn clients_get_their_own_multi_throttles() {
+    let (mock, _bridge, addr) = start_with(Config::default()).await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+    let a = ThrottleId::try_from('A').unwrap();
+    let acquire = |number| {
+        WiMessage::new(
+            LocoAddress::short(number).unwrap(),
+            WiMessageType::AddAddress,
+        )
+        .with_throttle(a)
+    };
+
+    let mut first = connect(addr, None).await.unwrap();
+    send(&mut first, 1, acquire(3)).await;
+    assert_eq!(next_line(&mock).await, "MA+S3<;>S3");
+
+    // The second client's A is kept apart from the first's on JMRI's connection
+    let mut second = connect(addr, None).await.unwrap();
+    send(&mut second, 1, acquire(4)).await;
+    assert_eq!(next_line(&mock).await, "MB+S4<;>S4");
+    expect(&mut second, |m| {
+        matches!(m, ServerMessage::Update(message)
+            if message.throttle == a && message.message_type == WiMessageType::Velocity(Velocity::STOP))
+    })
+    .await;
+
+    let stop = WiMessage::new(Target::All, WiMessageType::EmergencyStop).with_throttle(a);
+    send(&mut second, 2, stop).await;
+    assert_eq!(next_line(&mock).await, "MBAS4<;>X");
+
+    mock.send("MBA*<;>R0");
+    expect(&mut second, |m| {
+        matches!(m, ServerMessage::Update(message)
+            if message.throttle == a
+                && message.message_type == WiMessageType::Direction(Direction::Reverse))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn clock_is_sent_to_every_client() {
+    let (mock, bridge, mut ws) = start().await;
+    mock.set_clock(1234);
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Time(1234, ClockRate::REAL_TIME))
+    })
+    .await;
+    assert_eq!(*bridge.time.read().await, (1234, ClockRate::REAL_TIME));
+}
+
+#[tokio::test]
+async fn server_info_and_alerts_reach_clients() {
+    let (mock, _bridge, mut ws) = start().await;
+    expect(
+        &mut ws,
+        |m| matches!(m, ServerMessage::ServerInfo(info) if info.web_port == Some(12080)),
+    )
+    .await;
+
+    mock.send("HMTrack power is off");
+    expect(&mut ws, |m| {
+        matches!(m, ServerMessage::Notification(Notification::Alert(alert)) if alert == "Track power is off")
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn metrics_count_traffic_and_clients() {
+    let (mock, bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Velocity(Velocity::STOP))
+    })
+    .await;
+
+    let response = warp::test::request()
+        .path("/metrics")
+        .reply(&routes(bridge))
+        .await;
+    let text = String::from_utf8(response.body().to_vec()).unwrap();
+    assert!(text.contains("jmri_bridge_clients 1"));
+    assert!(text.contains("jmri_bridge_acquired_addresses 1"));
+    assert!(text.contains("jmri_bridge_jmri_connects_total 1"));
+    assert!(text.contains("jmri_bridge_client_commands_total{result=\"accepted\"} 1"));
+    assert!(text.contains("jmri_bridge_jmri_lines_total{direction=\"from_jmri\"}"));
+    // The handshake and acquiring are normal traffic, not parse errors
+    assert!(!text.contains("jmri_bridge_parse_errors_total{source=\"jmri\"}"));
+}
+
+#[tokio::test]
+async fn reconnects_and_acquires_again_after_jmri_drops() {
+    let (mock, bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+
+    mock.disconnect();
+    assert!(next_line(&mock).await.starts_with("HU"));
+    assert_eq!(next_line(&mock).await, "NTestThrottleRs");
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+
+    let response = warp::test::request()
+        .path("/metrics")
+        .reply(&routes(bridge))
+        .await;
+    let text = String::from_utf8(response.body().to_vec()).unwrap();
+    assert!(text.contains("jmri_bridge_jmri_connects_total 2"));
+}
+
+#[tokio::test]
+async fn commands_queued_while_jmri_is_away_are_dropped() {
+    let (mock, bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    let s3 = LocoAddress::short(3).unwrap();
+    send(&mut ws, 1, WiMessage::new(s3, WiMessageType::AddAddress)).await;
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+
+    // Left in the queue when the connection dropped, as if JMRI had stopped reading
+    mock.disconnect();
+    wait_until(|| !bridge.jmri_connected.load(Ordering::Relaxed)).await;
+    let speed = WiMessage::new(
+        s3,
+        WiMessageType::Velocity(Velocity::try_from(100).unwrap()),
+    );
+    let release = WiMessage::new(LocoAddress::short(4).unwrap(), WiMessageType::RemoveAddress);
+    let to_jmri = &bridge.to_jmri.tx;
+    to_jmri.send(speed.to_string()).await.unwrap();
+    to_jmri.send(format!("{speed}\n{release}")).await.unwrap();
+
+    assert!(next_line(&mock).await.starts_with("HU"));
+    assert_eq!(next_line(&mock).await, "NTestThrottleRs");
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+    assert_eq!(next_line(&mock).await, release.to_string());
+    // What's sent next is the next thing asked for, not the stale speed
+    wait_until(|| bridge.jmri_connected.load(Ordering::Relaxed)).await;
+    let stop = WiMessage::new(s3, WiMessageType::Velocity(Velocity::STOP));
+    send(&mut ws, 2, stop).await;
+    assert_eq!(next_line(&mock).await, stop.to_string());
+}
+
+#[tokio::test]
+async fn readiness_follows_the_jmri_connection() {
+    let (mock, bridge, _ws) = start().await;
+    next_line(&mock).await;
+    let routes = routes(bridge.clone());
+    let readiness = || async {
+        let response = warp::test::request()
+            .path("/health/ready")
+            .reply(&routes)
+            .await;
+        let readiness: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), readiness)
+    };
+
+    // Ready once JMRI has answered a heartbeat
+    wait_until(|| {
+        bridge
+            .last_heartbeat
+            .try_read()
+            .is_ok_and(|last| last.is_some())
+    })
+    .await;
+    let (status, ready) = readiness().await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(ready["jmri_connected"], true);
+    assert_eq!(ready["heartbeat"], "ok");
+    assert_eq!(ready["clients"], 1);
+
+    mock.disconnect();
+    wait_until(|| !bridge.jmri_connected.load(Ordering::Relaxed)).await;
+    let (status, ready) = readiness().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready["jmri_connected"], false);
+
+    // Liveness doesn't care
+    let response = warp::test::request().path("/health").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Back once the bridge has reconnected
+    wait_until(|| bridge.jmri_connected.load(Ordering::Relaxed)).await;
+    assert_eq!(readiness().await.0, StatusCode::OK);
+}
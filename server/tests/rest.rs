@@ -0,0 +1,140 @@
+mod common;
+
+use common::{next_line, start, TIMEOUT};
+use serde_json::{json, Value};
+use server::routes::routes;
+use server::Bridge;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use warp::http::StatusCode;
+
+async fn request(
+    bridge: &Arc<Bridge>,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let mut request = warp::test::request().method(method).path(path);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.reply(&routes(bridge.clone())).await;
+    let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+    (response.status(), body)
+}
+
+/// Polls `path` until its JSON matches `predicate`, since JMRI's replies arrive asynchronously.
+async fn eventually(bridge: &Arc<Bridge>, path: &str, predicate: impl Fn(&Value) -> bool) -> Value {
+    timeout(TIMEOUT, async {
+        loop {
+            let (_, body) = request(bridge, "GET", path, None).await;
+            if predicate(&body) {
+                return body;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn lists_roster_turnouts_and_clock() {
+    let (mock, bridge, _ws) = start().await;
+
+    let roster = eventually(&bridge, "/api/roster", |body| body != &json!([])).await;
+    assert_eq!(
+        roster[1],
+        json!({"name": "Mock Mainline", "address": {"number": 4014, "kind": "Long"}})
+    );
+
+    let turnouts = eventually(&bridge, "/api/turnouts", |body| body != &json!([])).await;
+    assert_eq!(turnouts[0]["state"], "Closed");
+    mock.send("PTA4LT1");
+    eventually(&bridge, "/api/turnouts", |body| {
+        body[0]["state"] == "Thrown"
+    })
+    .await;
+
+    let closed = Some(json!({"state": "Closed"}));
+    let (status, _) = request(&bridge, "PUT", "/api/turnouts/LT2", closed).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    eventually(&bridge, "/api/turnouts", |body| {
+        body[1]["state"] == "Closed"
+    })
+    .await;
+    let unknown = Some(json!({"state": "Unknown"}));
+    let (status, _) = request(&bridge, "PUT", "/api/turnouts/LT2", unknown).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    mock.set_clock(1700000000);
+    eventually(&bridge, "/api/clock", |body| {
+        body["time"] == 1700000000 && body["rate"] == 1.0
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn drives_an_acquired_address() {
+    let (mock, bridge, _ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    // Reading and failed commands don't start a client for the user
+    let speed = Some(json!({"velocity": 40}));
+    let (status, body) = request(&bridge, "PUT", "/api/locos/3/speed", speed.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "Address S3 is not acquired");
+    let (status, _) = request(&bridge, "GET", "/api/locos", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(bridge.rest_clients.read().await.is_empty());
+
+    let (status, _) = request(&bridge, "POST", "/api/locos/3", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+
+    let (status, _) = request(&bridge, "PUT", "/api/locos/3/speed", speed).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(next_line(&mock).await, "MTAS3<;>V40");
+
+    let direction = Some(json!({"direction": "Reverse"}));
+    request(&bridge, "PUT", "/api/locos/3/direction", direction).await;
+    assert_eq!(next_line(&mock).await, "MTAS3<;>R0");
+
+    request(&bridge, "POST", "/api/locos/3/functions/0", None).await;
+    assert_eq!(next_line(&mock).await, "MTAS3<;>F10");
+
+    let (status, body) = request(&bridge, "POST", "/api/locos/3/functions/69", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body["error"],
+        "Couldn't parse command: Invalid function 69, expected 0 to 68"
+    );
+    let (status, body) = request(&bridge, "POST", "/api/locos/S200", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body["error"],
+        "Couldn't parse command: Invalid short address 200, expected 1 to 127"
+    );
+
+    let locos = eventually(&bridge, "/api/locos", |body| {
+        body[0]["functions"] == json!([0])
+    })
+    .await;
+    assert_eq!(
+        locos,
+        json!([{"address": {"number": 3, "kind": "Short"}, "velocity": 40, "direction": "Reverse", "functions": [0]}])
+    );
+
+    let (status, _) = request(&bridge, "POST", "/api/estop", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(next_line(&mock).await, "MTA*<;>X");
+
+    request(&bridge, "DELETE", "/api/locos/3", None).await;
+    assert_eq!(next_line(&mock).await, "MT-S3<;>S3");
+    eventually(&bridge, "/api/locos", |body| body == &json!([])).await;
+    // Holding nothing ends the user's client
+    assert!(bridge.rest_clients.read().await.is_empty());
+    assert_eq!(bridge.clients.read().await.len(), 1);
+}
@@ -0,0 +1,55 @@
+use futures::{SinkExt, StreamExt};
+use jmri_throttle_rs::protocol::{Hello, ServerMessage};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use server::routes::routes;
+use server::tls::Identity;
+use server::{Bridge, Config};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+
+/// A client that trusts only the bridge's self-signed certificate.
+fn trusting(identity: &Identity) -> Connector {
+    let mut roots = RootCertStore::empty();
+    for der in rustls_pemfile::certs(&mut identity.cert.as_slice()).unwrap() {
+        roots.add(&Certificate(der)).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Connector::Rustls(Arc::new(config))
+}
+
+#[tokio::test]
+async fn websocket_over_tls() {
+    let bridge = Bridge::new(Config {
+        advertise: false,
+        ..Config::default()
+    });
+    let identity = Identity::self_signed(vec!["localhost".to_string()]).unwrap();
+    let connector = trusting(&identity);
+
+    let (addr, server) = warp::serve(routes(bridge))
+        .tls()
+        .cert(identity.cert)
+        .key(identity.key)
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let url = format!("wss://localhost:{}/ws", addr.port());
+    let (mut ws, _) = connect_async_tls_with_config(url, None, false, Some(connector))
+        .await
+        .unwrap();
+    let hello = serde_json::to_string(&Hello::default()).unwrap();
+    ws.send(Message::text(hello)).await.unwrap();
+
+    let message = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+    let Some(Ok(Message::Text(text))) = message else {
+        panic!("Expected a text message, got {message:?}");
+    };
+    let message: ServerMessage = serde_json::from_str(&text).unwrap();
+    assert!(matches!(message, ServerMessage::Welcome { .. }));
+}
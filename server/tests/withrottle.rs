@@ -0,0 +1,103 @@
+mod common;
+
+use common::{expect, is_update, next_line, send, start, start_withrottle, wait_until, TIMEOUT};
+use futures::{SinkExt, StreamExt};
+use jmri_throttle_rs::message::{LocoAddress, Velocity, WiMessage, WiMessageType};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, LinesCodec};
+
+type Throttle = Framed<TcpStream, LinesCodec>;
+
+/// Waits for the line `expected`, skipping everything else.
+async fn expect_line(throttle: &mut Throttle, expected: &str) {
+    timeout(TIMEOUT, async {
+        while let Some(Ok(line)) = throttle.next().await {
+            if line == expected {
+                return;
+            }
+        }
+        panic!("Throttle disconnected");
+    })
+    .await
+    .unwrap()
+}
+
+/// Reads the next line the throttle gets.
+async fn next_throttle_line(throttle: &mut Throttle) -> String {
+    timeout(TIMEOUT, throttle.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn native_throttle_gets_the_roster_and_turnouts() {
+    let (_mock, bridge, _ws) = start().await;
+    wait_until(|| {
+        let listed = |len: Option<usize>| len.is_some_and(|len| len > 0);
+        listed(bridge.roster.try_read().ok().map(|r| r.len()))
+            && listed(bridge.turnouts.try_read().ok().map(|t| t.len()))
+    })
+    .await;
+
+    let addr = start_withrottle(&bridge).await;
+    let mut throttle = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+    assert_eq!(next_throttle_line(&mut throttle).await, "VN2.0");
+    assert_eq!(
+        next_throttle_line(&mut throttle).await,
+        r"RL2]\[Mock Switcher}|{3}|{S]\[Mock Mainline}|{4014}|{L"
+    );
+    assert_eq!(
+        next_throttle_line(&mut throttle).await,
+        r"PTL]\[LT1}|{Yard Lead}|{2]\[LT2}|{Mainline Siding}|{4"
+    );
+    assert_eq!(bridge.metrics.client_connections.get(), 2);
+}
+
+#[tokio::test]
+async fn native_throttle_shares_the_jmri_connection() {
+    let (mock, bridge, mut ws) = start().await;
+    next_line(&mock).await;
+    next_line(&mock).await;
+
+    let addr = start_withrottle(&bridge).await;
+    let mut throttle = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+    expect_line(&mut throttle, "VN2.0").await;
+
+    throttle.send("NEngine Driver").await.unwrap();
+    throttle.send("MT+S3<;>S3").await.unwrap();
+    assert_eq!(next_line(&mock).await, "MT+S3<;>S3");
+    expect_line(&mut throttle, "MTAS3<;>V0").await;
+
+    throttle.send("MTAS3<;>V30").await.unwrap();
+    assert_eq!(next_line(&mock).await, "MTAS3<;>V30");
+    expect_line(&mut throttle, "MTAS3<;>V30").await;
+
+    // A WebSocket client taking the address over releases it on the native throttle
+    send(
+        &mut ws,
+        1,
+        WiMessage::new(LocoAddress::short(3).unwrap(), WiMessageType::AddAddress),
+    )
+    .await;
+    // Its own T is sent as A, as the native throttle has T on JMRI's connection
+    assert_eq!(next_line(&mock).await, "MT-S3<;>S3");
+    assert_eq!(next_line(&mock).await, "MA+S3<;>S3");
+    expect_line(&mut throttle, "MT-S3<;>S3").await;
+    expect(&mut ws, |m| {
+        is_update(m, WiMessageType::Velocity(Velocity::STOP))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn native_throttle_gets_errors_as_alerts() {
+    let (_mock, bridge, _ws) = start().await;
+    let addr = start_withrottle(&bridge).await;
+    let mut throttle = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+
+    throttle.send("MTAS4<;>V5").await.unwrap();
+    expect_line(&mut throttle, "HMAddress S4 is not acquired").await;
+}
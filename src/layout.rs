@@ -0,0 +1,186 @@
+use crate::message::{AddressKind, LocoAddress};
+use serde::{Deserialize, Serialize};
+
+const ENTRY_SEPARATOR: &str = "]\\[";
+const FIELD_SEPARATOR: &str = "}|{";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct RosterEntry {
+    pub name: String,
+    pub address: LocoAddress,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TurnoutState {
+    Unknown,
+    Closed,
+    Thrown,
+}
+
+impl TurnoutState {
+    fn from_code(code: &str) -> Self {
+        match code {
+            "2" => TurnoutState::Closed,
+            "4" => TurnoutState::Thrown,
+            _ => TurnoutState::Unknown,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            TurnoutState::Unknown => "1",
+            TurnoutState::Closed => "2",
+            TurnoutState::Thrown => "4",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Turnout {
+    pub system_name: String,
+    pub user_name: String,
+    pub state: TurnoutState,
+}
+
+/// Splits a WiThrottle list line, e.g. `RL2]\[a}|{b]\[c}|{d`, into the fields of each entry.
+fn entries(line: &str) -> impl Iterator<Item = Vec<&str>> {
+    line.split(ENTRY_SEPARATOR)
+        .skip(1)
+        .map(|entry| entry.split(FIELD_SEPARATOR).collect())
+}
+
+/// Parses JMRI's roster list, `RL<count>]\[<name>}|{<address>}|{<S|L>...`.
+pub fn parse_roster(line: &str) -> Option<Vec<RosterEntry>> {
+    if !line.starts_with("RL") {
+        return None;
+    }
+    let roster = entries(line)
+        .filter_map(|fields| match fields[..] {
+            [name, address, kind] => Some(RosterEntry {
+                name: name.to_string(),
+                address: format!("{kind}{address}").parse().ok()?,
+            }),
+            _ => None,
+        })
+        .collect();
+    Some(roster)
+}
+
+/// Parses JMRI's turnout list, `PTL]\[<system name>}|{<user name>}|{<state>...`.
+pub fn parse_turnouts(line: &str) -> Option<Vec<Turnout>> {
+    if !line.starts_with("PTL") {
+        return None;
+    }
+    let turnouts = entries(line)
+        .filter_map(|fields| match fields[..] {
+            [system_name, user_name, state] => Some(Turnout {
+                system_name: system_name.to_string(),
+                user_name: user_name.to_string(),
+                state: TurnoutState::from_code(state),
+            }),
+            _ => None,
+        })
+        .collect();
+    Some(turnouts)
+}
+
+/// Formats a roster list like JMRI sends it, for native throttles.
+pub fn format_roster(roster: &[RosterEntry]) -> String {
+    let mut line = format!("RL{}", roster.len());
+    for entry in roster {
+        let kind = match entry.address.kind() {
+            AddressKind::Short => "S",
+            AddressKind::Long => "L",
+        };
+        let fields = [&entry.name, &entry.address.number().to_string(), kind];
+        line.push_str(ENTRY_SEPARATOR);
+        line.push_str(&fields.join(FIELD_SEPARATOR));
+    }
+    line
+}
+
+/// Formats a turnout list like JMRI sends it, for native throttles.
+pub fn format_turnouts(turnouts: &[Turnout]) -> String {
+    let mut line = "PTL".to_string();
+    for turnout in turnouts {
+        let fields = [
+            turnout.system_name.as_str(),
+            &turnout.user_name,
+            turnout.state.code(),
+        ];
+        line.push_str(ENTRY_SEPARATOR);
+        line.push_str(&fields.join(FIELD_SEPARATOR));
+    }
+    line
+}
+
+/// Formats a command asking JMRI to close or throw a turnout, `None` for [TurnoutState::Unknown].
+pub fn format_turnout_command(system_name: &str, state: TurnoutState) -> Option<String> {
+    let action = match state {
+        TurnoutState::Closed => 'C',
+        TurnoutState::Thrown => 'T',
+        TurnoutState::Unknown => return None,
+    };
+    Some(format!("PTA{action}{system_name}"))
+}
+
+/// Parses a turnout state change, `PTA<state><system name>`.
+pub fn parse_turnout_update(line: &str) -> Option<(String, TurnoutState)> {
+    let rest = line.strip_prefix("PTA")?;
+    let mut chars = rest.chars();
+    let state = chars.next()?;
+    Some((
+        chars.as_str().to_string(),
+        TurnoutState::from_code(&state.to_string()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roster() {
+        let roster = parse_roster(r"RL2]\[RGS 41}|{41}|{L]\[Test Loco}|{3}|{S").unwrap();
+        assert_eq!(
+            roster,
+            vec![
+                RosterEntry {
+                    name: "RGS 41".into(),
+                    address: LocoAddress::long(41).unwrap(),
+                },
+                RosterEntry {
+                    name: "Test Loco".into(),
+                    address: LocoAddress::short(3).unwrap(),
+                },
+            ]
+        );
+        assert_eq!(parse_roster("RL0"), Some(vec![]));
+        assert_eq!(parse_roster("PTL"), None);
+
+        let line = format_roster(&roster);
+        assert_eq!(line, r"RL2]\[RGS 41}|{41}|{L]\[Test Loco}|{3}|{S");
+        assert_eq!(parse_roster(&line), Some(roster));
+    }
+
+    #[test]
+    fn turnouts() {
+        let turnouts = parse_turnouts(r"PTL]\[LT12}|{Rico Station N}|{1]\[LT324}|{}|{4").unwrap();
+        assert_eq!(turnouts.len(), 2);
+        assert_eq!(turnouts[0].user_name, "Rico Station N");
+        assert_eq!(turnouts[0].state, TurnoutState::Unknown);
+        assert_eq!(turnouts[1].system_name, "LT324");
+        assert_eq!(turnouts[1].state, TurnoutState::Thrown);
+        assert_eq!(parse_turnouts(&format_turnouts(&turnouts)), Some(turnouts));
+
+        assert_eq!(
+            parse_turnout_update("PTA2LT12"),
+            Some(("LT12".to_string(), TurnoutState::Closed))
+        );
+        assert_eq!(
+            format_turnout_command("LT12", TurnoutState::Thrown).as_deref(),
+            Some("PTATLT12")
+        );
+        assert_eq!(format_turnout_command("LT12", TurnoutState::Unknown), None);
+    }
+}
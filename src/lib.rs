@@ -1 +1,4 @@
+pub mod layout;
 pub mod message;
+pub mod protocol;
+pub mod server_info;
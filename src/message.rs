@@ -1,10 +1,363 @@
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{MapAccess, Unexpected, Visitor};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
 pub type Address = i32;
-pub type Velocity = i16;
-pub type Function = u8;
+
+/// Addresses below this are taken to be short when nothing says which kind they are.
+pub const DEFAULT_SHORT_ADDRESS_LIMIT: Address = 128;
+
+/// A number outside the range DCC or WiThrottle allows for `field`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidValue {
+    pub field: &'static str,
+    pub value: i64,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl InvalidValue {
+    fn check(field: &'static str, value: i64, min: i64, max: i64) -> Result<(), Self> {
+        match (min..=max).contains(&value) {
+            true => Ok(()),
+            false => Err(Self {
+                field,
+                value,
+                min,
+                max,
+            }),
+        }
+    }
+}
+
+impl Display for InvalidValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid {} {}, expected {} to {}",
+            self.field, self.value, self.min, self.max
+        )
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+/// A speed step, 0 to 126.
+#[derive(
+    Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord,
+)]
+#[serde(try_from = "i16", into = "i16")]
+pub struct Velocity(u8);
+
+impl Velocity {
+    pub const STOP: Self = Self(0);
+    pub const MAX: Self = Self(126);
+
+    /// `velocity` clamped into the valid range, for adjusting a speed by some delta.
+    pub fn saturating(velocity: i32) -> Self {
+        Self(velocity.clamp(0, Self::MAX.0.into()) as u8)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<i16> for Velocity {
+    type Error = InvalidValue;
+
+    fn try_from(velocity: i16) -> Result<Self, Self::Error> {
+        InvalidValue::check("velocity", velocity.into(), 0, Self::MAX.0.into())?;
+        Ok(Self(velocity as u8))
+    }
+}
+
+impl From<Velocity> for i16 {
+    fn from(velocity: Velocity) -> Self {
+        velocity.0.into()
+    }
+}
+
+impl Display for Velocity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How many times faster than real time the fast clock runs, e.g. 4.0. Always finite and not
+/// negative, JMRI's clock can be stopped but not run backwards.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(try_from = "f32", into = "f32")]
+pub struct ClockRate(f32);
+
+// Comparing rates is fine as they're never NaN
+impl Eq for ClockRate {}
+
+impl ClockRate {
+    pub const REAL_TIME: Self = Self(1.0);
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+impl TryFrom<f32> for ClockRate {
+    type Error = String;
+
+    fn try_from(rate: f32) -> Result<Self, Self::Error> {
+        match rate.is_finite() && rate >= 0.0 {
+            true => Ok(Self(rate)),
+            false => Err(format!("Invalid fast clock rate {rate}")),
+        }
+    }
+}
+
+impl From<ClockRate> for f32 {
+    fn from(rate: ClockRate) -> Self {
+        rate.0
+    }
+}
+
+impl Display for ClockRate {
+    /// Always with a decimal point, like JMRI writes it, e.g. `4.0`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// A function number, F0 to F68 like JMRI supports.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Function(u8);
+
+impl Function {
+    pub const MAX: Self = Self(68);
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for Function {
+    type Error = InvalidValue;
+
+    fn try_from(function: u8) -> Result<Self, Self::Error> {
+        InvalidValue::check("function", function.into(), 0, Self::MAX.0.into())?;
+        Ok(Self(function))
+    }
+}
+
+impl From<Function> for u8 {
+    fn from(function: Function) -> Self {
+        function.0
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Function {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let function: u8 = s
+            .parse()
+            .map_err(|e| format!("Couldn't translate function: {e}, Function: {s}"))?;
+        Function::try_from(function).map_err(|e| e.to_string())
+    }
+}
+
+/// Whether a DCC address is a short (two digit) or long (four digit) one. The same number can
+/// be either, e.g. S3 and L3 are different locomotives.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum AddressKind {
+    Short,
+    Long,
+}
+
+impl AddressKind {
+    pub fn max_number(self) -> Address {
+        match self {
+            AddressKind::Short => 127,
+            AddressKind::Long => 10239,
+        }
+    }
+
+    fn field(self) -> &'static str {
+        match self {
+            AddressKind::Short => "short address",
+            AddressKind::Long => "long address",
+        }
+    }
+}
+
+/// A DCC address that's within range for its kind. Number 0 is DCC's broadcast address, which
+/// never belongs to a locomotive.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "RawLocoAddress")]
+pub struct LocoAddress {
+    number: Address,
+    kind: AddressKind,
+}
+
+#[derive(Deserialize)]
+struct RawLocoAddress {
+    number: Address,
+    kind: AddressKind,
+}
+
+impl TryFrom<RawLocoAddress> for LocoAddress {
+    type Error = InvalidValue;
+
+    fn try_from(raw: RawLocoAddress) -> Result<Self, Self::Error> {
+        Self::new(raw.number, raw.kind)
+    }
+}
+
+impl LocoAddress {
+    pub fn new(number: Address, kind: AddressKind) -> Result<Self, InvalidValue> {
+        InvalidValue::check(kind.field(), number.into(), 1, kind.max_number().into())?;
+        Ok(Self { number, kind })
+    }
+
+    pub fn short(number: Address) -> Result<Self, InvalidValue> {
+        Self::new(number, AddressKind::Short)
+    }
+
+    pub fn long(number: Address) -> Result<Self, InvalidValue> {
+        Self::new(number, AddressKind::Long)
+    }
+
+    pub fn number(self) -> Address {
+        self.number
+    }
+
+    pub fn kind(self) -> AddressKind {
+        self.kind
+    }
+
+    /// For numbers entered without a kind: short below `short_limit`, long from there on.
+    pub fn with_default_kind(number: Address, short_limit: Address) -> Result<Self, InvalidValue> {
+        match number < short_limit {
+            true => Self::short(number),
+            false => Self::long(number),
+        }
+    }
+
+    /// Parses `S3`/`L3`, or a bare number with the kind picked by [Self::with_default_kind].
+    pub fn parse_with_default(s: &str, short_limit: Address) -> Result<Self, String> {
+        match s.parse::<Address>() {
+            Ok(number) => Self::with_default_kind(number, short_limit).map_err(|e| e.to_string()),
+            Err(_) => s.parse(),
+        }
+    }
+}
+
+impl Display for LocoAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            AddressKind::Short => 'S',
+            AddressKind::Long => 'L',
+        };
+        write!(f, "{kind}{}", self.number)
+    }
+}
+
+impl FromStr for LocoAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let kind = match chars.next() {
+            Some('S') => AddressKind::Short,
+            Some('L') => AddressKind::Long,
+            _ => return Err(format!("Address has no S or L prefix: {s}")),
+        };
+        let number = chars
+            .as_str()
+            .parse()
+            .map_err(|e| format!("Couldn't translate address: {e}, Address: {s}"))?;
+        Self::new(number, kind).map_err(|e| e.to_string())
+    }
+}
+
+/// Which locomotives on a throttle a message is about: one address, or `*` for all of them.
+/// Serialized like a [LocoAddress], with `"*"` for all.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Target {
+    Loco(LocoAddress),
+    All,
+}
+
+const WILDCARD: &str = "*";
+
+impl From<LocoAddress> for Target {
+    fn from(address: LocoAddress) -> Self {
+        Target::Loco(address)
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Loco(address) => address.fmt(f),
+            Target::All => f.write_str(WILDCARD),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            WILDCARD => Ok(Target::All),
+            s => s.parse().map(Target::Loco),
+        }
+    }
+}
+
+impl Serialize for Target {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Target::Loco(address) => address.serialize(serializer),
+            Target::All => serializer.serialize_str(WILDCARD),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetVisitor;
+
+        impl<'de> Visitor<'de> for TargetVisitor {
+            type Value = Target;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                write!(f, "an address or \"{WILDCARD}\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Target, E> {
+                match s {
+                    WILDCARD => Ok(Target::All),
+                    s => Err(E::invalid_value(Unexpected::Str(s), &self)),
+                }
+            }
+
+            // Handed on so out of range addresses keep their error
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Target, A::Error> {
+                LocoAddress::deserialize(MapAccessDeserializer::new(map)).map(Target::Loco)
+            }
+        }
+
+        deserializer.deserialize_any(TargetVisitor)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub enum Direction {
@@ -31,6 +384,52 @@ impl FromStr for Direction {
     }
 }
 
+/// One of the multi-throttles of a WiThrottle connection, the `T` in `MTAS3<;>V5`. Each holds
+/// its own addresses, like the A and B throttles of a handheld.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[serde(try_from = "char", into = "char")]
+pub struct ThrottleId(char);
+
+impl ThrottleId {
+    /// Used by messages that don't say, and by everything before multi-throttles.
+    pub const DEFAULT: ThrottleId = ThrottleId('T');
+
+    pub fn get(self) -> char {
+        self.0
+    }
+}
+
+impl Default for ThrottleId {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TryFrom<char> for ThrottleId {
+    type Error = String;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        match c.is_ascii_alphanumeric() {
+            true => Ok(Self(c)),
+            false => Err(format!(
+                "Invalid throttle {c:?}, expected a letter or digit"
+            )),
+        }
+    }
+}
+
+impl From<ThrottleId> for char {
+    fn from(throttle: ThrottleId) -> Self {
+        throttle.0
+    }
+}
+
+impl Display for ThrottleId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Copy, Clone)]
 pub enum WiMessageType {
     AddAddress,
@@ -39,7 +438,12 @@ pub enum WiMessageType {
     FunctionPressed(Function),
     FunctionReleased(Function), // TODO: Maybe remove FunctionReleased as FunctionPressed always toggles in JMRI
     Direction(Direction),
-    Time(i64),
+    /// Stops immediately, ignoring momentum. JMRI reports it as speed -1.
+    EmergencyStop,
+    /// The fast clock's time in seconds since the epoch and how fast it's running.
+    Time(i64, ClockRate),
+    /// JMRI's code for the speed steps the decoder uses, 1 being 128 steps.
+    SpeedStepMode(u8),
 }
 
 impl WiMessageType {
@@ -56,30 +460,46 @@ impl FromStr for WiMessageType {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut chars = s.chars();
-        let first = chars.next().unwrap();
-        let nums: String = chars.filter(|c| c.is_numeric()).collect();
+        let first = chars.next().ok_or("Empty action")?;
+        let rest = chars.as_str();
 
         match first {
             'V' => {
-                let negative = if s.contains('-') { -1 } else { 1 };
+                let velocity: i16 = rest
+                    .parse()
+                    .map_err(|e| format!("Couldn't translate velocity: {e}, Action: {s}"))?;
+                // JMRI reports an emergency stop as -1
+                if velocity == -1 {
+                    return Ok(WiMessageType::EmergencyStop);
+                }
                 Ok(WiMessageType::Velocity(
-                    negative * nums.parse::<i16>().unwrap(),
+                    Velocity::try_from(velocity).map_err(|e| e.to_string())?,
                 ))
             }
+            's' => {
+                let mode = rest
+                    .parse()
+                    .map_err(|e| format!("Couldn't translate speed step mode: {e}, Action: {s}"))?;
+                Ok(WiMessageType::SpeedStepMode(mode))
+            }
             'F' => {
-                let mut nums = nums.chars();
-                let is_pressed = nums.next().unwrap_or('0') == '1';
-                let nums = nums.collect::<String>().parse().unwrap();
+                let is_pressed = match rest.chars().next() {
+                    Some('1') => true,
+                    Some('0') => false,
+                    _ => return Err(format!("Function is neither pressed nor released: {s}")),
+                };
+                let function = Function::from_str(&rest[1..])?;
                 if is_pressed {
-                    Ok(WiMessageType::FunctionPressed(nums))
+                    Ok(WiMessageType::FunctionPressed(function))
                 } else {
-                    Ok(WiMessageType::FunctionReleased(nums))
+                    Ok(WiMessageType::FunctionReleased(function))
                 }
             }
             'R' => {
-                let dir = Direction::from_str(&nums).unwrap();
+                let dir = Direction::from_str(rest).unwrap();
                 Ok(WiMessageType::Direction(dir))
             }
+            'X' => Ok(WiMessageType::EmergencyStop),
             _ => Err(format!("No action found in str: {s}")),
         }
     }
@@ -94,6 +514,8 @@ impl Display for WiMessageType {
             FunctionPressed(func) => format!("F1{func}"),
             FunctionReleased(func) => format!("F0{func}"),
             Direction(dir) => dir.to_string(),
+            EmergencyStop => 'X'.into(),
+            SpeedStepMode(mode) => format!("s{mode}"),
             AddAddress => '+'.into(),
             RemoveAddress => '-'.into(),
             _ => String::new(),
@@ -103,31 +525,44 @@ impl Display for WiMessageType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct WiMessage {
     pub message_type: WiMessageType,
-    pub address: Address,
+    pub address: Target,
+    /// Left out by clients that only ever use one throttle.
+    #[serde(default)]
+    pub throttle: ThrottleId,
 }
 
 impl WiMessage {
-    pub fn new(address: Address, message_type: WiMessageType) -> Self {
+    pub fn new(address: impl Into<Target>, message_type: WiMessageType) -> Self {
         Self {
-            address,
+            address: address.into(),
             message_type,
+            throttle: ThrottleId::DEFAULT,
         }
     }
+
+    pub fn with_throttle(self, throttle: ThrottleId) -> Self {
+        Self { throttle, ..self }
+    }
+
+    /// A fast clock update, which isn't about any address.
+    pub fn time(time: i64, rate: ClockRate) -> Self {
+        Self::new(Target::All, WiMessageType::Time(time, rate))
+    }
 }
 
 impl Display for WiMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let address_type = if self.address < 128 { 'S' } else { 'L' };
+        if let WiMessageType::Time(time, rate) = self.message_type {
+            return write!(f, "PFT{time}<;>{rate}");
+        }
+        let (address, throttle) = (self.address, self.throttle);
         let s = if self.message_type.is_address() {
-            format!(
-                "MT{}{address_type}{}<;>{address_type}{}",
-                self.message_type, self.address, self.address
-            )
+            format!("M{throttle}{}{address}<;>{address}", self.message_type)
         } else {
-            format!("MTA{address_type}{}<;>{}", self.address, self.message_type)
+            format!("M{throttle}A{address}<;>{}", self.message_type)
         };
 
         f.write_str(&s)
@@ -138,46 +573,53 @@ impl FromStr for WiMessage {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut split = s.split("<;>");
-        let address = split.next().unwrap();
-
-        let address_action = if address.contains('-') {
-            Some(WiMessageType::RemoveAddress)
-        } else if address.contains('+') {
-            Some(WiMessageType::AddAddress)
-        } else if s.starts_with("PFT") {
-            let time = s.split("<;>").next().unwrap();
-            let time: String = time.chars().filter(|c| c.is_numeric()).collect();
-            let time: i64 = time.parse().unwrap();
-            return Ok(WiMessage {
-                address: 0,
-                message_type: WiMessageType::Time(time),
-            });
-        } else {
-            None
-        };
+        if let Some(time) = s.strip_prefix("PFT") {
+            // PFT<seconds><;><rate>
+            let mut split = time.split("<;>");
+            let time: i64 = split
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|e| format!("Couldn't translate time: {e}, Time: {s}"))?;
+            let rate = match split.next() {
+                Some(rate) => rate
+                    .parse::<f32>()
+                    .map_err(|e| e.to_string())
+                    .and_then(ClockRate::try_from)
+                    .map_err(|e| format!("Couldn't translate clock rate: {e}, Time: {s}"))?,
+                None => ClockRate::REAL_TIME,
+            };
+            return Ok(WiMessage::time(time, rate));
+        }
 
-        let address: String = address.chars().filter(|c| c.is_numeric()).collect();
-        let address: Address = address
-            .parse()
-            .map_err(|e| format!("Couldn't translate address: {e}, Message: {s}"))?;
+        let mut split = s.split("<;>");
+        // M<throttle><action><address>, e.g. MTAS3 or MT+L4014
+        let mut head = split
+            .next()
+            .unwrap()
+            .strip_prefix('M')
+            .ok_or_else(|| format!("Not a throttle message: {s}"))?
+            .chars();
+        let throttle = head
+            .next()
+            .ok_or_else(|| format!("No throttle: {s}"))
+            .and_then(ThrottleId::try_from)?;
+        let action = head.next();
+        let address = Target::from_str(head.as_str())?;
 
-        if let Some(message_type) = address_action {
-            return Ok(WiMessage {
-                message_type,
-                address,
-            });
+        let message_type = match action {
+            Some('+') => WiMessageType::AddAddress,
+            Some('-') => WiMessageType::RemoveAddress,
+            Some('A') => match split.next() {
+                Some(action) if !action.is_empty() => WiMessageType::from_str(action)?,
+                _ => return Err(format!("Empty action: {s}")),
+            },
+            _ => return Err(format!("Unknown throttle action: {s}")),
         };
-
-        let action = split.next();
-        if action.is_none() {
-            return Err(format!("Empty action: {s}"));
-        }
-
-        let message_type = WiMessageType::from_str(action.unwrap())?;
         Ok(WiMessage {
             message_type,
             address,
+            throttle,
         })
     }
 }
@@ -185,6 +627,7 @@ impl FromStr for WiMessage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn direction_display() {
@@ -196,33 +639,259 @@ mod tests {
     fn wi_message_type_display() {
         assert_eq!(format!("{}", WiMessageType::AddAddress), "+");
         assert_eq!(format!("{}", WiMessageType::RemoveAddress), "-");
-        assert_eq!(format!("{}", WiMessageType::Velocity(5)), "V5");
-        assert_eq!(format!("{}", WiMessageType::FunctionPressed(5)), "F15");
-        assert_eq!(format!("{}", WiMessageType::FunctionReleased(5)), "F05");
+        assert_eq!(
+            format!(
+                "{}",
+                WiMessageType::Velocity(Velocity::try_from(5).unwrap())
+            ),
+            "V5"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                WiMessageType::FunctionPressed(Function::try_from(5).unwrap())
+            ),
+            "F15"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                WiMessageType::FunctionReleased(Function::try_from(5).unwrap())
+            ),
+            "F05"
+        );
         assert_eq!(
             format!("{}", WiMessageType::Direction(Direction::Reverse)),
             "R0"
         );
+        assert_eq!(format!("{}", WiMessageType::EmergencyStop), "X");
     }
 
     #[test]
     fn wi_message_display() {
         let wi_message = WiMessage {
             message_type: WiMessageType::AddAddress,
-            address: 5,
+            address: LocoAddress::short(5).unwrap().into(),
+            throttle: ThrottleId::DEFAULT,
         };
         assert_eq!(format!("{}", wi_message), "MT+S5<;>S5");
         let wi_message = WiMessage {
-            message_type: WiMessageType::FunctionReleased(10),
-            address: 128,
+            message_type: WiMessageType::FunctionReleased(Function::try_from(10).unwrap()),
+            address: LocoAddress::long(128).unwrap().into(),
+            throttle: ThrottleId::DEFAULT,
         };
         assert_eq!(format!("{}", wi_message), "MTAL128<;>F010");
+        let wi_message = WiMessage {
+            throttle: ThrottleId::try_from('B').unwrap(),
+            ..wi_message
+        };
+        assert_eq!(format!("{}", wi_message), "MBAL128<;>F010");
+        let wi_message = WiMessage::time(1234, ClockRate::REAL_TIME);
+        assert_eq!(format!("{}", wi_message), "PFT1234<;>1.0");
+        let wi_message = WiMessage::time(1234, ClockRate::try_from(0.25).unwrap());
+        assert_eq!(format!("{}", wi_message), "PFT1234<;>0.25");
+        let fast = WiMessage::from_str("PFT1700000000<;>4.0").unwrap();
+        assert_eq!(fast.to_string(), "PFT1700000000<;>4.0");
+    }
+
+    #[test]
+    fn multi_throttles() {
+        let message = WiMessage::from_str("M0AS3<;>V5").unwrap();
+        assert_eq!(message.throttle, ThrottleId::try_from('0').unwrap());
+        assert_eq!(message.to_string(), "M0AS3<;>V5");
+        assert!(WiMessage::from_str("M*AS3<;>V5").is_err());
+        assert!(WiMessage::from_str("M").is_err());
+
+        // Clients from before multi-throttles leave the throttle out
+        let json = r#"{"message_type":"AddAddress","address":{"number":3,"kind":"Short"}}"#;
+        let message: WiMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(message.throttle, ThrottleId::DEFAULT);
+    }
+
+    #[test]
+    fn short_and_long_addresses_are_kept_apart() {
+        let short = WiMessage::from_str("MTAS3<;>V40").unwrap();
+        let long = WiMessage::from_str("MTAL3<;>V40").unwrap();
+        assert_eq!(short.address, LocoAddress::short(3).unwrap().into());
+        assert_eq!(long.address, LocoAddress::long(3).unwrap().into());
+        assert_eq!(long.to_string(), "MTAL3<;>V40");
+
+        let added = WiMessage::from_str("MT+L4014<;>").unwrap();
+        assert_eq!(added.message_type, WiMessageType::AddAddress);
+        assert_eq!(added.address, LocoAddress::long(4014).unwrap().into());
+        assert!(WiMessage::from_str("MTA3<;>V40").is_err());
+    }
+
+    #[test]
+    fn wildcard_address() {
+        let stop = WiMessage::from_str("MTA*<;>V0").unwrap();
+        assert_eq!(stop.address, Target::All);
+        assert_eq!(stop.message_type, WiMessageType::Velocity(Velocity::STOP));
+        assert_eq!(
+            WiMessage::new(Target::All, WiMessageType::EmergencyStop).to_string(),
+            "MTA*<;>X"
+        );
+
+        let json = serde_json::to_string(&stop).unwrap();
+        assert_eq!(
+            json,
+            r#"{"message_type":{"Velocity":0},"address":"*","throttle":"T"}"#
+        );
+        assert_eq!(serde_json::from_str::<WiMessage>(&json).unwrap(), stop);
+        let json = r#"{"message_type":{"Velocity":0},"address":"S3"}"#;
+        assert!(serde_json::from_str::<WiMessage>(json).is_err());
+    }
+
+    #[test]
+    fn default_address_kind() {
+        let limit = DEFAULT_SHORT_ADDRESS_LIMIT;
+        assert_eq!(
+            LocoAddress::parse_with_default("127", limit),
+            LocoAddress::short(127).map_err(|e| e.to_string())
+        );
+        assert_eq!(
+            LocoAddress::parse_with_default("128", limit),
+            LocoAddress::long(128).map_err(|e| e.to_string())
+        );
+        assert_eq!(
+            LocoAddress::parse_with_default("L3", limit),
+            LocoAddress::long(3).map_err(|e| e.to_string())
+        );
+        assert_eq!(
+            LocoAddress::parse_with_default("50", 100),
+            LocoAddress::short(50).map_err(|e| e.to_string())
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_name_the_field() {
+        assert_eq!(
+            Velocity::try_from(127).unwrap_err().to_string(),
+            "Invalid velocity 127, expected 0 to 126"
+        );
+        assert_eq!(Function::try_from(69).unwrap_err().field, "function");
+        assert_eq!(LocoAddress::short(128).unwrap_err().field, "short address");
+        assert_eq!(LocoAddress::long(10240).unwrap_err().field, "long address");
+        assert!(LocoAddress::long(-1).is_err());
+        assert!(LocoAddress::short(0).is_err());
+        assert!(LocoAddress::long(0).is_err());
+
+        assert_eq!(
+            WiMessage::from_str("MTAS3<;>V200").unwrap_err(),
+            "Invalid velocity 200, expected 0 to 126"
+        );
+        assert!(WiMessage::from_str("MTAS3<;>F1100").is_err());
+        assert!(WiMessage::from_str("MTAS300<;>V5").is_err());
+        assert_eq!(
+            WiMessage::from_str("MTAS3<;>V-1").unwrap().message_type,
+            WiMessageType::EmergencyStop
+        );
+        assert_eq!(
+            WiMessage::from_str("MTAS3<;>s1").unwrap().message_type,
+            WiMessageType::SpeedStepMode(1)
+        );
+        assert!(WiMessage::from_str("MTAS3<;>s").is_err());
+    }
+
+    #[test]
+    fn deserialize_rejects_out_of_range_values() {
+        let json = r#"{"message_type":{"Velocity":127},"address":{"number":3,"kind":"Short"}}"#;
+        let error = serde_json::from_str::<WiMessage>(json).unwrap_err();
+        assert!(error.to_string().contains("Invalid velocity 127"));
+
+        let json = r#"{"message_type":"AddAddress","address":{"number":200,"kind":"Short"}}"#;
+        let error = serde_json::from_str::<WiMessage>(json).unwrap_err();
+        assert!(error.to_string().contains("Invalid short address 200"));
+
+        let json = r#"{"message_type":{"Velocity":126},"address":{"number":200,"kind":"Long"}}"#;
+        assert!(serde_json::from_str::<WiMessage>(json).is_ok());
+    }
+
+    fn loco_address() -> impl Strategy<Value = LocoAddress> {
+        prop_oneof![
+            (1..=AddressKind::Short.max_number()).prop_map(|n| LocoAddress::short(n).unwrap()),
+            (1..=AddressKind::Long.max_number()).prop_map(|n| LocoAddress::long(n).unwrap()),
+        ]
+    }
+
+    fn function() -> impl Strategy<Value = Function> {
+        (0..=Function::MAX.get()).prop_map(|f| Function::try_from(f).unwrap())
+    }
+
+    /// Every variant but [WiMessageType::Time], which has no address.
+    fn throttle_message_type() -> impl Strategy<Value = WiMessageType> {
+        prop_oneof![
+            Just(WiMessageType::AddAddress),
+            Just(WiMessageType::RemoveAddress),
+            Just(WiMessageType::EmergencyStop),
+            (0..=i16::from(Velocity::MAX))
+                .prop_map(|v| WiMessageType::Velocity(Velocity::try_from(v).unwrap())),
+            function().prop_map(WiMessageType::FunctionPressed),
+            function().prop_map(WiMessageType::FunctionReleased),
+            prop_oneof![Just(Direction::Reverse), Just(Direction::Forward)]
+                .prop_map(WiMessageType::Direction),
+            any::<u8>().prop_map(WiMessageType::SpeedStepMode),
+        ]
+    }
+
+    fn target() -> impl Strategy<Value = Target> {
+        prop_oneof![loco_address().prop_map(Target::Loco), Just(Target::All)]
+    }
+
+    fn throttle_id() -> impl Strategy<Value = ThrottleId> {
+        prop_oneof![
+            prop::char::range('0', '9'),
+            prop::char::range('A', 'Z'),
+            prop::char::range('a', 'z'),
+        ]
+        .prop_map(|c| ThrottleId::try_from(c).unwrap())
+    }
+
+    fn clock_rate() -> impl Strategy<Value = ClockRate> {
+        (0.0f32..1000.0).prop_map(|rate| ClockRate::try_from(rate).unwrap())
+    }
+
+    fn wi_message() -> impl Strategy<Value = WiMessage> {
+        prop_oneof![
+            (target(), throttle_message_type(), throttle_id()).prop_map(
+                |(address, message_type, throttle)| {
+                    WiMessage::new(address, message_type).with_throttle(throttle)
+                }
+            ),
+            (any::<i64>(), clock_rate()).prop_map(|(time, rate)| WiMessage::time(time, rate)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn wire_format_round_trips(message in wi_message()) {
+            prop_assert_eq!(WiMessage::from_str(&message.to_string()), Ok(message));
+        }
+
+        #[test]
+        fn serde_round_trips(message in wi_message()) {
+            let json = serde_json::to_string(&message).unwrap();
+            prop_assert_eq!(serde_json::from_str::<WiMessage>(&json).unwrap(), message);
+            let bytes = rmp_serde::to_vec_named(&message).unwrap();
+            prop_assert_eq!(rmp_serde::from_slice::<WiMessage>(&bytes).unwrap(), message);
+        }
+
+        #[test]
+        fn parsing_never_panics(line in "(M[T0-9][AL+-]|PFT)?[SL*]?-?[0-9]{0,6}(<;>)?[VFRXs]?-?[0-9]{0,6}.*") {
+            let _ = WiMessage::from_str(&line);
+        }
+
+        #[test]
+        fn out_of_range_velocities_are_rejected(velocity in 127i16..) {
+            let line = format!("MTAS3<;>V{velocity}");
+            prop_assert!(WiMessage::from_str(&line).is_err());
+        }
     }
 
     #[test]
     fn wi_message_type_is_address() {
         assert!(WiMessageType::AddAddress.is_address());
         assert!(WiMessageType::RemoveAddress.is_address());
-        assert!(!WiMessageType::Velocity(5).is_address());
+        assert!(!WiMessageType::Velocity(Velocity::try_from(5).unwrap()).is_address());
     }
 }
@@ -0,0 +1,280 @@
+use crate::message::{Address, AddressKind, LocoAddress, WiMessage};
+use crate::server_info::{Notification, ServerInfo};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+pub type RequestId = u32;
+
+/// mDNS service type the server advertises its WebSocket endpoint as, with the path in the
+/// `path` TXT property.
+pub const BRIDGE_SERVICE: &str = "_jmri-throttle._tcp.local.";
+
+/// A command sent from a client to the server. The request ID is optional and, when present,
+/// is echoed back in the matching [ServerMessage::Ack] or [ServerMessage::Error].
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct ClientMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<RequestId>,
+    #[serde(flatten)]
+    pub message: WiMessage,
+}
+
+impl ClientMessage {
+    pub fn new(request_id: Option<RequestId>, message: WiMessage) -> Self {
+        Self {
+            request_id,
+            message,
+        }
+    }
+}
+
+/// The first message a client sends on every connection, logging in before any command. It's
+/// sent after connecting, rather than in the URL, so the token stays out of access logs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Hello {
+    /// PIN or token, when the server requires one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// What a logged in user may do. The default allows everything but administering the bridge,
+/// which has to be granted explicitly to a configured user.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(default)]
+pub struct Permissions {
+    /// Inclusive ranges of addresses that may be acquired, each for short or long addresses
+    /// only, any address when empty.
+    pub addresses: Vec<(AddressKind, Address, Address)>,
+    /// May take an address from another throttle holding it, which is told it was released.
+    pub steal: bool,
+    /// May stop every loco on the layout at once, not just its own.
+    pub power: bool,
+    /// May throw and close turnouts.
+    pub turnouts: bool,
+    /// May watch the layout but not send any commands.
+    pub view_only: bool,
+    /// May see every session and release addresses or disconnect clients.
+    pub admin: bool,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self {
+            addresses: Vec::new(),
+            steal: true,
+            power: true,
+            turnouts: true,
+            view_only: false,
+            admin: false,
+        }
+    }
+}
+
+impl Permissions {
+    pub fn may_acquire(&self, address: LocoAddress) -> bool {
+        self.addresses.is_empty()
+            || self.addresses.iter().any(|(kind, from, to)| {
+                *kind == address.kind() && (*from..=*to).contains(&address.number())
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum CommandError {
+    NotOwner(LocoAddress),
+    JmriDisconnected,
+    RateLimited,
+    Parse(String),
+    Unauthorized,
+    ViewOnly,
+    AddressNotPermitted(LocoAddress),
+    AddressInUse(LocoAddress),
+    AdminOnly,
+    NoFreeThrottle,
+    PowerNotPermitted,
+    TurnoutsNotPermitted,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotOwner(address) => write!(f, "Address {address} is not acquired"),
+            CommandError::JmriDisconnected => f.write_str("JMRI is not connected"),
+            CommandError::RateLimited => f.write_str("Too many commands, slow down"),
+            CommandError::Parse(e) => write!(f, "Couldn't parse command: {e}"),
+            CommandError::Unauthorized => f.write_str("Wrong PIN or token"),
+            CommandError::ViewOnly => f.write_str("This login is view-only"),
+            CommandError::AddressNotPermitted(address) => {
+                write!(f, "Not permitted to acquire address {address}")
+            }
+            CommandError::AddressInUse(address) => {
+                write!(f, "Address {address} is in use by another throttle")
+            }
+            CommandError::AdminOnly => f.write_str("Only admins can do that"),
+            CommandError::NoFreeThrottle => f.write_str("Every multi-throttle is in use"),
+            CommandError::PowerNotPermitted => f.write_str("Not permitted to stop the layout"),
+            CommandError::TurnoutsNotPermitted => f.write_str("Not permitted to change turnouts"),
+        }
+    }
+}
+
+/// Everything the server sends to a client over the WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerMessage {
+    /// Sent first on every connection, with who the client is logged in as.
+    Welcome {
+        user: String,
+        permissions: Permissions,
+    },
+    Update(WiMessage),
+    Ack {
+        request_id: RequestId,
+    },
+    Error {
+        request_id: Option<RequestId>,
+        error: CommandError,
+    },
+    /// Sent after [ServerMessage::Welcome] and again whenever JMRI's info changes.
+    ServerInfo(ServerInfo),
+    Notification(Notification),
+}
+
+/// Wire encoding of a WebSocket connection, chosen by the client with the `encoding` query
+/// parameter when connecting. JSON stays the default as it's readable in browser dev tools.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+/// A single encoded WebSocket message.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Payload {
+        match self {
+            Encoding::Json => Payload::Text(serde_json::to_string(value).unwrap()),
+            Encoding::MsgPack => Payload::Binary(rmp_serde::to_vec_named(value).unwrap()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Json => f.write_str("json"),
+            Encoding::MsgPack => f.write_str("msgpack"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MsgPack),
+            _ => Err(format!("Unknown encoding: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Function, Velocity, WiMessageType};
+
+    #[test]
+    fn client_message_request_id_is_optional() {
+        let json = r#"{"message_type":"AddAddress","address":{"number":3,"kind":"Short"}}"#;
+        let message: ClientMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(message.request_id, None);
+        assert_eq!(
+            message.message.address,
+            LocoAddress::short(3).unwrap().into()
+        );
+
+        let json = r#"{"request_id":7,"message_type":{"Velocity":20},"address":{"number":3,"kind":"Short"}}"#;
+        let message: ClientMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(message.request_id, Some(7));
+        assert_eq!(
+            message.message.message_type,
+            WiMessageType::Velocity(Velocity::try_from(20).unwrap())
+        );
+    }
+
+    #[test]
+    fn encoding_round_trip() {
+        let message = ClientMessage::new(
+            Some(3),
+            WiMessage::new(
+                LocoAddress::long(128).unwrap(),
+                WiMessageType::FunctionPressed(Function::try_from(2).unwrap()),
+            ),
+        );
+        for encoding in [Encoding::Json, Encoding::MsgPack] {
+            let bytes = match encoding.encode(&message) {
+                Payload::Text(text) => text.into_bytes(),
+                Payload::Binary(bytes) => bytes,
+            };
+            let decoded: ClientMessage = encoding.decode(&bytes).unwrap();
+            assert_eq!(decoded.request_id, Some(3));
+            assert_eq!(
+                decoded.message.address,
+                LocoAddress::long(128).unwrap().into()
+            );
+            assert_eq!(
+                decoded.message.message_type,
+                WiMessageType::FunctionPressed(Function::try_from(2).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn command_error_display() {
+        assert_eq!(
+            CommandError::NotOwner(LocoAddress::short(3).unwrap()).to_string(),
+            "Address S3 is not acquired"
+        );
+        assert_eq!(
+            CommandError::JmriDisconnected.to_string(),
+            "JMRI is not connected"
+        );
+    }
+
+    #[test]
+    fn permitted_address_ranges() {
+        let long = |number| LocoAddress::long(number).unwrap();
+        let short = |number| LocoAddress::short(number).unwrap();
+        assert!(Permissions::default().may_acquire(long(9999)));
+
+        let permissions: Permissions = serde_json::from_str(
+            r#"{"addresses":[["Short",1,99],["Long",3000,3099]],"steal":false}"#,
+        )
+        .unwrap();
+        assert!(permissions.may_acquire(short(3)));
+        assert!(permissions.may_acquire(long(3050)));
+        assert!(!permissions.may_acquire(short(100)));
+        // The same number as the other kind is a different locomotive
+        assert!(!permissions.may_acquire(long(3)));
+        assert!(!permissions.steal);
+        assert!(permissions.power && permissions.turnouts);
+        assert!(!permissions.admin);
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+/// What JMRI says about itself when a throttle connects.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct ServerInfo {
+    /// WiThrottle protocol version, from `VN`.
+    pub protocol_version: Option<String>,
+    /// Port of JMRI's web server, from `PW`.
+    pub web_port: Option<u16>,
+    /// Server type, from `HT`, e.g. `JMRI`.
+    pub server_type: Option<String>,
+    /// Free text description including JMRI's version and railroad name, from `Ht`.
+    pub description: Option<String>,
+}
+
+/// A message JMRI wants shown to the user.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum Notification {
+    /// `HM`, for things that need attention.
+    Alert(String),
+    /// `Hm`
+    Info(String),
+}
+
+/// One of the `VN`, `PW`, `HT`, `Ht`, `HM` and `Hm` lines.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ServerInfoLine {
+    ProtocolVersion(String),
+    WebPort(u16),
+    ServerType(String),
+    Description(String),
+    Notification(Notification),
+}
+
+/// Parses a server info line, returning `None` for any other line.
+pub fn parse_server_info(line: &str) -> Option<ServerInfoLine> {
+    let (prefix, rest) = (line.get(..2)?, line.get(2..)?.to_string());
+    let parsed = match prefix {
+        "VN" => ServerInfoLine::ProtocolVersion(rest),
+        "PW" => ServerInfoLine::WebPort(rest.parse().ok()?),
+        "HT" => ServerInfoLine::ServerType(rest),
+        "Ht" => ServerInfoLine::Description(rest),
+        "HM" => ServerInfoLine::Notification(Notification::Alert(rest)),
+        "Hm" => ServerInfoLine::Notification(Notification::Info(rest)),
+        _ => return None,
+    };
+    Some(parsed)
+}
+
+impl ServerInfo {
+    /// Updates the info from a line, handing back notifications as they aren't kept.
+    pub fn apply(&mut self, line: ServerInfoLine) -> Option<Notification> {
+        match line {
+            ServerInfoLine::ProtocolVersion(version) => self.protocol_version = Some(version),
+            ServerInfoLine::WebPort(port) => self.web_port = Some(port),
+            ServerInfoLine::ServerType(server_type) => self.server_type = Some(server_type),
+            ServerInfoLine::Description(description) => self.description = Some(description),
+            ServerInfoLine::Notification(notification) => return Some(notification),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_info_lines() {
+        let mut info = ServerInfo::default();
+        for line in ["VN2.0", "PW12080", "HTJMRI", "HtJMRI v5.4 My Railroad"] {
+            assert_eq!(info.apply(parse_server_info(line).unwrap()), None);
+        }
+        assert_eq!(
+            info,
+            ServerInfo {
+                protocol_version: Some("2.0".into()),
+                web_port: Some(12080),
+                server_type: Some("JMRI".into()),
+                description: Some("JMRI v5.4 My Railroad".into()),
+            }
+        );
+
+        assert_eq!(
+            parse_server_info("HMTrack power is off"),
+            Some(ServerInfoLine::Notification(Notification::Alert(
+                "Track power is off".into()
+            )))
+        );
+        assert_eq!(
+            parse_server_info("Hmwelcome"),
+            Some(ServerInfoLine::Notification(Notification::Info(
+                "welcome".into()
+            )))
+        );
+        assert_eq!(parse_server_info("MTAS3<;>V5"), None);
+        assert_eq!(parse_server_info("PWnot a port"), None);
+    }
+}
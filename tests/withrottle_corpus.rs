@@ -0,0 +1,44 @@
+//! Golden tests parsing real WiThrottle output from different JMRI versions, kept in
+//! `tests/fixtures` with the message each line is expected to parse to.
+
+use jmri_throttle_rs::message::WiMessage;
+use std::str::FromStr;
+
+fn check_corpus(name: &str, corpus: &str) {
+    let lines = corpus
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    for (number, line) in lines {
+        let location = format!("{name}:{}", number + 1);
+        let (raw, expected) = line
+            .split_once(" => ")
+            .unwrap_or_else(|| panic!("{location}: no expected value"));
+        let parsed = WiMessage::from_str(raw);
+        match expected {
+            "error" => assert!(parsed.is_err(), "{location}: {raw} parsed as {parsed:?}"),
+            expected => {
+                let expected: WiMessage = serde_json::from_str(expected)
+                    .unwrap_or_else(|e| panic!("{location}: bad expected value: {e}"));
+                assert_eq!(parsed, Ok(expected), "{location}: {raw}");
+                // Nothing parsed is lost when it's sent on
+                let sent = expected.to_string();
+                assert_eq!(
+                    WiMessage::from_str(&sent),
+                    Ok(expected),
+                    "{location}: {sent}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn jmri_4_24() {
+    check_corpus("jmri-4.24.txt", include_str!("fixtures/jmri-4.24.txt"));
+}
+
+#[test]
+fn jmri_5_4() {
+    check_corpus("jmri-5.4.txt", include_str!("fixtures/jmri-5.4.txt"));
+}